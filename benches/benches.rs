@@ -1,7 +1,10 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use tempfile::TempDir;
 
-use kiwi_store::{KiwiEngine, KiwiStore, SledStore};
+use kiwi_store::{KiwiEngine, KiwiStore, KiwiStoreOptions, ReadMode, SledStore};
 
 // TODO(tkarwowski): randomize test
 // TODO(tkarwowski): create random keys and values of length between 1 and 100000 bytes
@@ -41,6 +44,34 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     });
 
+    c.bench_function("kvs_read_mmap", |b| {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KiwiStoreOptions {
+            read_mode: ReadMode::Mmap,
+            ..KiwiStoreOptions::default()
+        };
+        let db = KiwiStore::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..100 {
+            let _ = db.set(format!("key{}", i), format!("value{}", i));
+        }
+        // move values out of the active segment, it's never mapped
+        drop(db);
+        let options = KiwiStoreOptions {
+            max_segment_size: 0,
+            read_mode: ReadMode::Mmap,
+            ..KiwiStoreOptions::default()
+        };
+        let db = KiwiStore::open_with_options(temp_dir.path(), options).unwrap();
+        let _ = db.set("roll".to_owned(), "over".to_owned());
+        b.iter(|| {
+            for _ in 0..10 {
+                for i in 0..100 {
+                    let _ = db.get(format!("key{}", i));
+                }
+            }
+        });
+    });
+
     c.bench_function("sled_read", |b| {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let db = SledStore::open(temp_dir.path()).unwrap();
@@ -57,5 +88,55 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+// Reads from several threads at once, while another thread keeps writing
+fn concurrent_read_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kvs_concurrent_read");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = KiwiStore::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        let _ = db.set(format!("key{}", i), format!("value{}", i));
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let db = db.clone();
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut i = 0u64;
+            while !stop.load(Ordering::Relaxed) {
+                let _ = db.set(format!("other{}", i % 100), format!("value{}", i));
+                i += 1;
+            }
+        })
+    };
+
+    for threads in [1, 2, 4, 8].iter().copied() {
+        group.throughput(Throughput::Elements(threads as u64 * 1000));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for _ in 0..threads {
+                            scope.spawn(|| {
+                                for _ in 0..10 {
+                                    for i in 0..100 {
+                                        let _ = db.get(format!("key{}", i));
+                                    }
+                                }
+                            });
+                        }
+                    });
+                });
+            },
+        );
+    }
+    group.finish();
+
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
+
+criterion_group!(benches, criterion_benchmark, concurrent_read_benchmark);
 criterion_main!(benches);
@@ -2,8 +2,13 @@ use clap::{arg, ArgMatches, Command};
 
 use color_eyre::Result;
 use kiwi_proto::kiwi_service_client::KiwiServiceClient;
-use kiwi_proto::{GetReply, GetRequest, RemoveRequest, SetRequest};
+use kiwi_proto::watch_event::Kind;
+use kiwi_proto::{
+    GetReply, GetRequest, IncrRequest, RemoveRequest, SetRequest, WatchEvent, WatchRequest,
+};
+use std::io::{self, Write};
 use std::process;
+use std::time::Duration;
 
 pub mod kiwi_proto {
     tonic::include_proto!("kiwi_store");
@@ -33,19 +38,76 @@ async fn main() -> Result<()> {
                 .about("Set value for key.")
                 .arg(arg!(<KEY>))
                 .arg(arg!(<VALUE>))
-                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'")),
+                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'"))
+                .arg(
+                    arg!(-n --namespace <NAME> "Namespace of the key, the default one if omitted")
+                        .required(false),
+                )
+                .arg(
+                    arg!(-t --ttl <SECONDS> "Time after which the key expires")
+                        .required(false)
+                        .validator(parse_ttl),
+                ),
         )
         .subcommand(
             Command::new("get")
                 .about("Get value for key.")
                 .arg(arg!(<KEY>))
-                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'")),
+                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'"))
+                .arg(
+                    arg!(-n --namespace <NAME> "Namespace of the key, the default one if omitted")
+                        .required(false),
+                ),
         )
         .subcommand(
             Command::new("rm")
                 .about("Remove key and value.")
                 .arg(arg!(<KEY>))
-                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'")),
+                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'"))
+                .arg(
+                    arg!(-n --namespace <NAME> "Namespace of the key, the default one if omitted")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("incr")
+                .about("Increment counter at key, creating it if missing.")
+                .arg(arg!(<KEY>))
+                .arg(
+                    arg!([DELTA] "Amount to add, 1 by default")
+                        .allow_hyphen_values(true)
+                        .validator(|delta| delta.parse::<i64>()),
+                )
+                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'"))
+                .arg(
+                    arg!(-n --namespace <NAME> "Namespace of the key, the default one if omitted")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("decr")
+                .about("Decrement counter at key, creating it if missing.")
+                .arg(arg!(<KEY>))
+                .arg(
+                    arg!([DELTA] "Amount to subtract, 1 by default")
+                        .allow_hyphen_values(true)
+                        .validator(|delta| delta.parse::<i64>()),
+                )
+                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'"))
+                .arg(
+                    arg!(-n --namespace <NAME> "Namespace of the key, the default one if omitted")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Print changes of keys as they happen, until interrupted.")
+                .arg(arg!([PREFIX] "Only keys starting with prefix, all keys by default"))
+                .arg(arg!(-a --addr <ADDRESS> "IP address either v4 or v6 in format 'IP:PORT'"))
+                .arg(
+                    arg!(-n --namespace <NAME> "Namespace of the key, the default one if omitted")
+                        .required(false),
+                ),
         )
         .get_matches();
 
@@ -60,38 +122,114 @@ async fn run(matches: ArgMatches) -> Result<()> {
 
     let address = subcommand_matches.value_of("addr").unwrap();
     let address = format!("http://{address}");
+    let namespace = subcommand_matches.value_of("namespace").map(str::to_owned);
 
     let mut client = KiwiServiceClient::connect(address).await?;
 
     match action {
         "get" => {
-            let key = subcommand_matches.value_of("KEY").unwrap().to_owned();
-            let request = tonic::Request::new(GetRequest { key });
+            let key = subcommand_matches.value_of("KEY").unwrap().into();
+            let request = tonic::Request::new(GetRequest { key, namespace });
             let response = client.get(request).await.unwrap();
             let GetReply { key_found, value } = response.into_inner();
             if key_found {
-                println!("{}", value);
+                // values are raw bytes, print them as they are
+                let mut stdout = io::stdout();
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
             } else {
                 println!("Key not found");
             }
         }
         "set" => {
-            let key = subcommand_matches.value_of("KEY").unwrap().to_owned();
-            let value = subcommand_matches.value_of("VALUE").unwrap().to_owned();
+            let key = subcommand_matches.value_of("KEY").unwrap().into();
+            let value = subcommand_matches.value_of("VALUE").unwrap().into();
+            let ttl_ms = subcommand_matches
+                .value_of("ttl")
+                .map(|ttl| parse_ttl(ttl).unwrap().as_millis() as u64);
 
-            let request = tonic::Request::new(SetRequest { key, value });
+            let request = tonic::Request::new(SetRequest {
+                key,
+                value,
+                ttl_ms,
+                namespace,
+            });
             let _response = client.set(request).await;
         }
         "rm" => {
-            let key = subcommand_matches.value_of("KEY").unwrap().to_owned();
+            let key = subcommand_matches.value_of("KEY").unwrap().into();
 
-            let request = tonic::Request::new(RemoveRequest { key });
+            let request = tonic::Request::new(RemoveRequest { key, namespace });
             let response = client.remove(request).await.unwrap();
             if !response.into_inner().key_found {
                 eprintln!("Key not found");
                 process::exit(1);
             }
         }
+        "incr" | "decr" => {
+            let key = subcommand_matches.value_of("KEY").unwrap().into();
+            let delta = subcommand_matches
+                .value_of("DELTA")
+                .map_or(1, |delta| delta.parse::<i64>().unwrap());
+            let delta = if action == "decr" {
+                delta.checked_neg().unwrap_or_else(|| {
+                    eprintln!("Delta out of range");
+                    process::exit(1);
+                })
+            } else {
+                delta
+            };
+
+            let request = tonic::Request::new(IncrRequest {
+                key,
+                delta,
+                namespace,
+            });
+            match client.incr(request).await {
+                Ok(response) => println!("{}", response.into_inner().value),
+                Err(status) => {
+                    eprintln!("{}", status.message());
+                    process::exit(1);
+                }
+            }
+        }
+        "watch" => {
+            let prefix = subcommand_matches
+                .value_of("PREFIX")
+                .unwrap_or_default()
+                .into();
+
+            let request = tonic::Request::new(WatchRequest { prefix, namespace });
+            let mut events = client.watch(request).await?.into_inner();
+            while let Some(event) = events.message().await? {
+                let WatchEvent {
+                    kind,
+                    key,
+                    value,
+                    seq,
+                } = event;
+                // one line per change: sequence number, operation, then raw key and value
+                let mut line = format!("{seq} ").into_bytes();
+                match Kind::from_i32(kind) {
+                    Some(Kind::Set) => {
+                        line.extend_from_slice(b"set ");
+                        line.extend_from_slice(&key);
+                        line.push(b' ');
+                        line.extend_from_slice(&value);
+                    }
+                    Some(Kind::Remove) => {
+                        line.extend_from_slice(b"rm ");
+                        line.extend_from_slice(&key);
+                    }
+                    // changes unknown to this client
+                    None => continue,
+                }
+                line.push(b'\n');
+                let mut stdout = io::stdout();
+                stdout.write_all(&line)?;
+                stdout.flush()?;
+            }
+        }
         _ => {
             println!("No such command");
             process::exit(1);
@@ -100,3 +238,10 @@ async fn run(matches: ArgMatches) -> Result<()> {
 
     Ok(())
 }
+
+/// Parse a TTL given in seconds, which has to be a finite, non-negative number.
+fn parse_ttl(ttl: &str) -> std::result::Result<Duration, String> {
+    let seconds = ttl.parse::<f64>().map_err(|error| error.to_string())?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{} isn't a finite, non-negative number of seconds", ttl))
+}
@@ -1,14 +1,29 @@
 use clap::{arg, Command};
+use kiwi_proto::batch_operation::Operation;
 use kiwi_proto::kiwi_service_server::{KiwiService, KiwiServiceServer};
-use kiwi_proto::{GetReply, GetRequest, RemoveReply, RemoveRequest, SetReply, SetRequest};
+use kiwi_proto::watch_event;
+use kiwi_proto::{
+    BatchReply, BatchRequest, CompareAndSwapReply, CompareAndSwapRequest, GetReply, GetRequest,
+    IncrReply, IncrRequest, RemoveReply, RemoveRequest, SetReply, SetRequest, WatchEvent,
+    WatchRequest,
+};
 use kiwi_store::Result as KvsResult;
-use kiwi_store::{Error, KiwiEngine, KiwiStore, SledStore};
-use log::{debug, info};
+use kiwi_store::{
+    ChangeEvent, CompareAndSwapError, Error, KiwiEngine, KiwiStore, MemoryStore, SledStore,
+    WriteBatch,
+};
+use log::{debug, error, info};
 
+use std::ffi::OsStr;
+use std::future;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 use std::{env, fs, str};
+use tokio::sync::mpsc;
+use tokio::{signal, task};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::Server;
 use tonic::{Request, Response, Status};
 
@@ -18,6 +33,12 @@ pub mod kiwi_proto {
 
 static DB_PATH: &str = "./database";
 
+/// Events sent ahead to a watching client, further ones wait in its watcher.
+const WATCH_BUFFER: usize = 128;
+
+/// How often a watcher without changes checks whether its client is still there.
+const WATCH_POLL: Duration = Duration::from_millis(500);
+
 #[derive(Debug, Default)]
 pub struct Kvs<E>
 where
@@ -33,6 +54,24 @@ where
     fn new(engine: E) -> Self {
         Kvs { engine }
     }
+
+    /// Engine scoped to `namespace`, the default namespace if unset.
+    fn engine(&self, namespace: Option<String>) -> KvsResult<E> {
+        match namespace {
+            Some(name) => self.engine.open_tree(&name),
+            None => Ok(self.engine.clone()),
+        }
+    }
+
+    /// Engine scoped to `namespace` for reads, `None` if the namespace doesn't exist.
+    ///
+    /// Reads never create a namespace, a missing one is as good as empty.
+    fn existing_engine(&self, namespace: Option<String>) -> KvsResult<Option<E>> {
+        match namespace {
+            Some(name) => self.engine.find_tree(&name),
+            None => Ok(Some(self.engine.clone())),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -40,17 +79,24 @@ impl<E> KiwiService for Kvs<E>
 where
     E: KiwiEngine + std::marker::Sync,
 {
+    type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
+
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetReply>, Status> {
         debug!("got request: {:?}", &request);
 
-        let reply = match self.engine.get(request.into_inner().key).unwrap() {
+        let GetRequest { key, namespace } = request.into_inner();
+        let value = match self.existing_engine(namespace).map_err(internal)? {
+            Some(engine) => engine.get_bytes(&key).unwrap(),
+            None => None,
+        };
+        let reply = match value {
             Some(value) => GetReply {
                 key_found: true,
                 value,
             },
             None => GetReply {
                 key_found: false,
-                value: String::default(),
+                value: Vec::default(),
             },
         };
 
@@ -60,10 +106,25 @@ where
     async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetReply>, Status> {
         debug!("got request: {:?}", &request);
 
-        let SetRequest { key, value } = request.into_inner();
-        debug!("{key}, {value}");
+        let SetRequest {
+            key,
+            value,
+            ttl_ms,
+            namespace,
+        } = request.into_inner();
+        let engine = self.engine(namespace).map_err(internal)?;
+        debug!(
+            "{}, {}",
+            String::from_utf8_lossy(&key),
+            String::from_utf8_lossy(&value)
+        );
 
-        self.engine.set(key, value).unwrap();
+        match ttl_ms {
+            Some(ttl_ms) => engine
+                .set_bytes_with_ttl(key, value, Duration::from_millis(ttl_ms))
+                .unwrap(),
+            None => engine.set_bytes(key, value).unwrap(),
+        }
 
         let reply = SetReply {};
 
@@ -76,13 +137,147 @@ where
     ) -> Result<Response<RemoveReply>, Status> {
         debug!("got request: {:?}", &request);
 
-        let reply = match self.engine.remove(request.into_inner().key) {
+        let RemoveRequest { key, namespace } = request.into_inner();
+        let engine = self.engine(namespace).map_err(internal)?;
+        let reply = match engine.remove_bytes(key) {
             Ok(()) => RemoveReply { key_found: true },
             Err(_) => RemoveReply { key_found: false },
         };
 
         Ok(Response::new(reply))
     }
+
+    async fn batch(&self, request: Request<BatchRequest>) -> Result<Response<BatchReply>, Status> {
+        debug!("got request: {:?}", &request);
+
+        let BatchRequest {
+            operations,
+            namespace,
+        } = request.into_inner();
+        let mut batch = WriteBatch::new();
+        for operation in operations {
+            let operation_namespace = match &operation.operation {
+                Some(Operation::Set(SetRequest { namespace, .. }))
+                | Some(Operation::Remove(RemoveRequest { namespace, .. })) => namespace,
+                None => &None,
+            };
+            if operation_namespace.is_some() && *operation_namespace != namespace {
+                return Err(Status::invalid_argument(
+                    "batch operation in another namespace than the batch",
+                ));
+            }
+            match operation.operation {
+                Some(Operation::Set(SetRequest {
+                    key, value, ttl_ms, ..
+                })) => match ttl_ms {
+                    Some(ttl_ms) => {
+                        batch.set_bytes_with_ttl(key, value, Duration::from_millis(ttl_ms))
+                    }
+                    None => batch.set_bytes(key, value),
+                },
+                Some(Operation::Remove(RemoveRequest { key, .. })) => batch.remove_bytes(key),
+                None => return Err(Status::invalid_argument("empty batch operation")),
+            }
+        }
+
+        let engine = self.engine(namespace).map_err(internal)?;
+        match engine.apply_batch(batch) {
+            Ok(()) => Ok(Response::new(BatchReply {})),
+            Err(error) => Err(Status::internal(error.to_string())),
+        }
+    }
+
+    async fn compare_and_swap(
+        &self,
+        request: Request<CompareAndSwapRequest>,
+    ) -> Result<Response<CompareAndSwapReply>, Status> {
+        debug!("got request: {:?}", &request);
+
+        let CompareAndSwapRequest {
+            key,
+            expected,
+            new_value,
+            namespace,
+        } = request.into_inner();
+        let engine = self.engine(namespace).map_err(internal)?;
+        let reply = match engine.compare_and_swap_bytes(key, expected, new_value) {
+            Ok(Ok(())) => CompareAndSwapReply {
+                swapped: true,
+                current: None,
+            },
+            Ok(Err(CompareAndSwapError { current })) => CompareAndSwapReply {
+                swapped: false,
+                current,
+            },
+            Err(error) => return Err(Status::internal(error.to_string())),
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn incr(&self, request: Request<IncrRequest>) -> Result<Response<IncrReply>, Status> {
+        debug!("got request: {:?}", &request);
+
+        let IncrRequest {
+            key,
+            delta,
+            namespace,
+        } = request.into_inner();
+        let engine = self.engine(namespace).map_err(internal)?;
+        match engine.incr_by_bytes(key, delta) {
+            Ok(value) => Ok(Response::new(IncrReply { value })),
+            Err(error @ Error::InvalidCounter(_)) => {
+                Err(Status::failed_precondition(error.to_string()))
+            }
+            Err(error) => Err(Status::internal(error.to_string())),
+        }
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        debug!("got request: {:?}", &request);
+
+        let WatchRequest { prefix, namespace } = request.into_inner();
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        let mut watcher = match self.existing_engine(namespace).map_err(internal)? {
+            Some(engine) => engine.watch_bytes(prefix).map_err(internal)?,
+            // nothing to watch, the stream ends right away
+            None => return Ok(Response::new(ReceiverStream::new(receiver))),
+        };
+        // watchers block, so they are drained on a thread of their own until the client hangs up
+        task::spawn_blocking(move || {
+            while !sender.is_closed() {
+                if let Some(event) = watcher.next_timeout(WATCH_POLL) {
+                    if sender.blocking_send(Ok(watch_event(event))).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+fn internal(error: Error) -> Status {
+    Status::internal(error.to_string())
+}
+
+fn watch_event(event: ChangeEvent) -> WatchEvent {
+    match event {
+        ChangeEvent::Set { key, value, seq } => WatchEvent {
+            kind: watch_event::Kind::Set as i32,
+            key,
+            value,
+            seq,
+        },
+        ChangeEvent::Remove { key, seq } => WatchEvent {
+            kind: watch_event::Kind::Remove as i32,
+            key,
+            value: Vec::new(),
+            seq,
+        },
+    }
 }
 
 #[tokio::main]
@@ -106,18 +301,23 @@ async fn main() -> KvsResult<()> {
                 .default_value("127.0.0.1:4000"),
         )
         .arg(
-            arg!(-e --engine <ENGINE> "Engine used for backend, either 'kvs' or 'sled'.")
+            arg!(-e --engine <ENGINE> "Engine used for backend, one of 'kvs', 'sled' or 'memory'.")
                 .required(false)
                 .default_value("kvs"),
         )
+        .arg(
+            arg!(-s --snapshot <DIR> "Directory the memory engine loads on startup and saves to on Ctrl-C.")
+                .required(false),
+        )
         .get_matches();
 
     let addr = matches.value_of("addr").unwrap();
     let engine = matches.value_of("engine").unwrap();
-    run(addr, engine).await
+    let snapshot = matches.value_of("snapshot");
+    run(addr, engine, snapshot).await
 }
 
-async fn run(address: &str, engine: &str) -> KvsResult<()> {
+async fn run(address: &str, engine: &str, snapshot: Option<&str>) -> KvsResult<()> {
     info!(
         "{} v{} running at {}",
         env!("CARGO_PKG_NAME"),
@@ -125,7 +325,7 @@ async fn run(address: &str, engine: &str) -> KvsResult<()> {
         address
     );
 
-    if !Path::new(DB_PATH).exists() {
+    if engine != "memory" && !Path::new(DB_PATH).exists() {
         fs::create_dir(DB_PATH)?
     }
 
@@ -142,7 +342,7 @@ async fn run(address: &str, engine: &str) -> KvsResult<()> {
             Ok(())
         }
         "sled" => {
-            if Path::new(DB_PATH).join("kvs.db").exists() {
+            if contains_kiwi_log(Path::new(DB_PATH))? {
                 return Err(Error::Other("kvs database already exists".to_owned()));
             }
             let kvs = Kvs::new(SledStore::open(DB_PATH)?);
@@ -152,8 +352,49 @@ async fn run(address: &str, engine: &str) -> KvsResult<()> {
                 .await?;
             Ok(())
         }
+        "memory" => {
+            let store = match snapshot {
+                Some(dir) => MemoryStore::load(dir)?,
+                None => MemoryStore::new(),
+            };
+            let kvs = Kvs::new(store.clone());
+            Server::builder()
+                .add_service(KiwiServiceServer::new(kvs))
+                .serve_with_shutdown(SocketAddr::from_str(address)?, shutdown())
+                .await?;
+            if let Some(dir) = snapshot {
+                info!("saving snapshot to {}", dir);
+                store.save(dir)?;
+            }
+            Ok(())
+        }
         _ => Err(Error::Other(
-            "unknown engine option, must be one of: kvs, sled".to_owned(),
+            "unknown engine option, must be one of: kvs, sled, memory".to_owned(),
         )),
     }
 }
+
+/// Resolves on Ctrl-C. If it can't be listened for, the server runs until killed.
+async fn shutdown() {
+    if let Err(error) = signal::ctrl_c().await {
+        error!(
+            "can't listen for Ctrl-C, snapshot won't be saved: {}",
+            error
+        );
+        future::pending::<()>().await;
+    }
+    info!("shutting down");
+}
+
+/// Whether `dir` holds a KiwiStore log, either segmented or in the legacy single file.
+fn contains_kiwi_log(dir: &Path) -> KvsResult<bool> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name() == Some(OsStr::new("kvs.db"))
+            || path.extension() == Some(OsStr::new("log"))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,61 @@
+//! Serialization formats for [`TypedStore`](crate::TypedStore) keys and values.
+//!
+//! [`Json`] is always available, `Bincode` and `MsgPack` are behind the `bincode` and `msgpack`
+//! features.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+pub trait Codec {
+    /// Serialize `value` into bytes stored in the engine.
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+    /// Deserialize bytes read from the engine, fails with [`Error::Codec`] if they don't hold a `T`.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Human readable, the same format the CLI tools use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|error| Error::Codec(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|error| Error::Codec(error.to_string()))
+    }
+}
+
+/// Compact binary format, see [bincode](https://docs.rs/bincode).
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|error| Error::Codec(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|error| Error::Codec(error.to_string()))
+    }
+}
+
+/// Self-describing binary format, see [MessagePack](https://msgpack.org).
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPack {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|error| Error::Codec(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(|error| Error::Codec(error.to_string()))
+    }
+}
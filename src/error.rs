@@ -15,10 +15,21 @@ pub enum Error {
     NoKey(String),
     /// Error when Seek fails due to file corruption
     Offset(String),
+    /// Error when a log record fails its checksum, holds the byte offset of the record
+    Corruption(u64),
     /// Error when any of the IO operation fails
     Io(io::Error),
     /// Error when deserialization failed due to file corruption
     InvalidData(serde_json::Error),
+    /// Error when a codec fails to encode or decode a typed key or value
+    Codec(String),
+    /// Error when a transaction read a key that has changed before it committed, holds the key
+    Conflict(Vec<u8>),
+    /// Error when reading a key as of a sequence number whose versions are no longer retained,
+    /// holds the sequence number
+    HistoryTruncated(u64),
+    /// Error when incrementing a value that isn't a counter, or when the counter overflows
+    InvalidCounter(String),
     /// Error when parsing utf-8 to string
     Utf8Error(str::Utf8Error),
     /// Error passed from Sled implementation of KvsEngine
@@ -40,8 +51,25 @@ impl Display for Error {
         match self {
             Error::NoKey(msg) => write!(f, "{}", msg),
             Error::Offset(msg) => write!(f, "{}", msg),
+            Error::Corruption(offset) => {
+                write!(f, "corrupted log record at byte offset {}", offset)
+            }
             Error::Io(msg) => write!(f, "{}", msg),
             Error::InvalidData(msg) => write!(f, "{}", msg),
+            Error::Codec(msg) => write!(f, "codec error: {}", msg),
+            Error::Conflict(key) => write!(
+                f,
+                "transaction conflict on key {:?}",
+                String::from_utf8_lossy(key)
+            ),
+            Error::HistoryTruncated(seq) => {
+                write!(
+                    f,
+                    "history at sequence number {} is no longer retained",
+                    seq
+                )
+            }
+            Error::InvalidCounter(msg) => write!(f, "invalid counter: {}", msg),
             Error::Utf8Error(msg) => write!(f, "{}", msg),
             Error::Sled(msg) => write!(f, "{}", msg),
             // Error::PoisonError(msg) => write!(f, "{}", msg),
@@ -6,9 +6,15 @@
 //! Nothing fancy, but should allow you to [KvStore::set], [KvStore::get] and [KvStore::remove]
 //! in a in-memory cache.
 // #![warn(missing_docs)]
+pub mod codec;
 mod error;
 mod store;
 pub mod thread_pool;
 
 pub use error::{Error, Result};
-pub use store::{KiwiEngine, KiwiStore, SledStore};
+pub use store::{
+    BytesIter, ChangeEvent, Changes, CompareAndSwapError, CompareAndSwapResult, Durability,
+    KeyVersion, KiwiEngine, KiwiSnapshot, KiwiStore, KiwiStoreOptions, KiwiStoreSnapshot, KvIter,
+    MemoryStore, MemoryStoreSnapshot, ReadMode, Retention, SledStore, SledStoreSnapshot,
+    Transaction, TypedStore, Watcher, WriteBatch,
+};
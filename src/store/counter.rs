@@ -0,0 +1,31 @@
+//! Counters updated by [`KiwiEngine::incr_by_bytes`](crate::KiwiEngine::incr_by_bytes).
+//!
+//! A counter is stored as the decimal text of a signed 64-bit integer, e.g. `-42`, so it can be read
+//! with `get` and set with `set` like any other value. A missing key counts as zero.
+use crate::{Error, Result};
+
+use std::str;
+
+/// Add `delta` to the counter stored as `current`, returning the new count and its encoding.
+pub(super) fn increment(current: Option<&[u8]>, delta: i64) -> Result<(i64, Vec<u8>)> {
+    let count = match current {
+        Some(current) => decode(current)?,
+        None => 0,
+    };
+    let count = count
+        .checked_add(delta)
+        .ok_or_else(|| Error::InvalidCounter(format!("adding {} to {} overflows", delta, count)))?;
+    Ok((count, count.to_string().into_bytes()))
+}
+
+fn decode(stored: &[u8]) -> Result<i64> {
+    str::from_utf8(stored)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| {
+            Error::InvalidCounter(format!(
+                "value {:?} isn't a 64-bit integer",
+                String::from_utf8_lossy(stored)
+            ))
+        })
+}
@@ -0,0 +1,91 @@
+//! Key expiry shared by both engines.
+//!
+//! Expiry is kept as an absolute point in time, in milliseconds since the Unix epoch, so that it
+//! survives restarts.
+use crate::Result;
+
+use log::error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often expired keys get swept by default.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Point in time `ttl` from now.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis() as u64)
+}
+
+pub(crate) fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now()
+}
+
+/// Time left until `expires_at`, zero if it's already in the past.
+pub(crate) fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now()))
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Runs a sweep of expired keys at a fixed interval on a background thread.
+///
+/// Dropping it stops the thread, waiting for a sweep in progress.
+#[derive(Debug)]
+pub(crate) struct Sweeper {
+    /// Set to `true` to stop the thread.
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /// Start calling `sweep` every `interval`, until it returns `false` or the sweeper is dropped.
+    pub(crate) fn start<F>(interval: Duration, mut sweep: F) -> Self
+    where
+        F: FnMut() -> Result<bool> + Send + 'static,
+    {
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_shutdown = Arc::clone(&shutdown);
+        let handle = thread::spawn(move || {
+            let (shutdown, wake_up) = &*thread_shutdown;
+            loop {
+                let guard = shutdown.lock().expect("error acquiring lock");
+                let (guard, _) = wake_up
+                    .wait_timeout_while(guard, interval, |shutdown| !*shutdown)
+                    .expect("error acquiring lock");
+                if *guard {
+                    break;
+                }
+                drop(guard);
+
+                match sweep() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(error) => error!("unable to sweep expired keys: {}", error),
+                }
+            }
+        });
+
+        Sweeper {
+            shutdown,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        let (shutdown, wake_up) = &*self.shutdown;
+        *shutdown.lock().expect("error acquiring lock") = true;
+        wake_up.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
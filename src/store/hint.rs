@@ -0,0 +1,141 @@
+//! Hint files, written next to compacted segments to rebuild the index without reading values.
+//!
+//! A hint file starts with [`MAGIC`], the length of the segment it describes and the sequence
+//! number the segment's history is complete from, followed by one entry per record in the segment:
+//!
+//! ```text
+//! | key_len: u32 | namespace: u32 | offset: u64 | len: u64 | expires_at: u64 | seq: u64 | timestamp: u64 | removed: u8 | key bytes |
+//! ```
+//!
+//! and ends with a CRC32 of everything before it. All integers are little-endian, `expires_at` is
+//! zero for keys that never expire. `removed` is one for the records of removes kept as history.
+use crate::store::record::Stamp;
+use crate::{Error, Result};
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+/// Bytes every hint file starts with, last byte is the format version.
+const MAGIC: &[u8; 5] = b"KIWH\x01";
+
+/// Location of a single record of the described segment.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HintEntry {
+    pub key: Vec<u8>,
+    pub namespace: u32,
+    pub offset: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+    pub stamp: Stamp,
+    pub removed: bool,
+}
+
+/// Atomically write hint file at `path` for a segment of `segment_len` bytes, whose history is
+/// complete from sequence number `horizon`.
+pub(crate) fn write(
+    path: &Path,
+    segment_len: u64,
+    horizon: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut write_hashed = |bytes: &[u8]| -> Result<()> {
+        hasher.update(bytes);
+        writer.write_all(bytes)?;
+        Ok(())
+    };
+
+    write_hashed(MAGIC)?;
+    write_hashed(&segment_len.to_le_bytes())?;
+    write_hashed(&horizon.to_le_bytes())?;
+    for entry in entries {
+        write_hashed(&(entry.key.len() as u32).to_le_bytes())?;
+        write_hashed(&entry.namespace.to_le_bytes())?;
+        write_hashed(&entry.offset.to_le_bytes())?;
+        write_hashed(&entry.len.to_le_bytes())?;
+        write_hashed(&entry.expires_at.unwrap_or(0).to_le_bytes())?;
+        write_hashed(&entry.stamp.seq.to_le_bytes())?;
+        write_hashed(&entry.stamp.timestamp.to_le_bytes())?;
+        write_hashed(&[entry.removed as u8])?;
+        write_hashed(&entry.key)?;
+    }
+
+    writer.write_all(&hasher.finalize().to_le_bytes())?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Read hint file at `path`, returns the horizon and the entries, or `Ok(None)` if it doesn't
+/// describe a segment of `segment_len` bytes anymore.
+pub(crate) fn read(path: &Path, segment_len: u64) -> Result<Option<(u64, Vec<HintEntry>)>> {
+    let mut content = Vec::new();
+    File::open(path)?.read_to_end(&mut content)?;
+
+    let corrupted = || Error::Other(format!("corrupted hint file {}", path.display()));
+    if content.len() < MAGIC.len() + 8 + 8 + 4 || &content[..MAGIC.len()] != MAGIC {
+        return Err(corrupted());
+    }
+    let (body, crc) = content.split_at(content.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(corrupted());
+    }
+
+    let mut body = &body[MAGIC.len()..];
+    if u64_from(&mut body) != segment_len {
+        return Ok(None);
+    }
+    let horizon = u64_from(&mut body);
+
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 4 + 4 + 8 + 8 + 8 + 8 + 8 + 1 {
+            return Err(corrupted());
+        }
+        let key_len = u32_from(&mut body) as usize;
+        let namespace = u32_from(&mut body);
+        let offset = u64_from(&mut body);
+        let len = u64_from(&mut body);
+        let expires_at = Some(u64_from(&mut body)).filter(|&expires_at| expires_at != 0);
+        let stamp = Stamp {
+            seq: u64_from(&mut body),
+            timestamp: u64_from(&mut body),
+        };
+        let removed = body[0] != 0;
+        body = &body[1..];
+        if body.len() < key_len {
+            return Err(corrupted());
+        }
+        let (key, rest) = body.split_at(key_len);
+        body = rest;
+        entries.push(HintEntry {
+            key: key.to_vec(),
+            namespace,
+            offset,
+            len,
+            expires_at,
+            stamp,
+            removed,
+        });
+    }
+
+    Ok(Some((horizon, entries)))
+}
+
+fn u32_from(bytes: &mut &[u8]) -> u32 {
+    let (int, rest) = bytes.split_at(4);
+    *bytes = rest;
+    u32::from_le_bytes([int[0], int[1], int[2], int[3]])
+}
+
+fn u64_from(bytes: &mut &[u8]) -> u64 {
+    let (int, rest) = bytes.split_at(8);
+    *bytes = rest;
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(int);
+    u64::from_le_bytes(buffer)
+}
@@ -0,0 +1,48 @@
+//! Past versions of keys, see [`KiwiEngine::history`](crate::KiwiEngine::history).
+use crate::store::expiry;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Which overwritten and removed versions of keys an engine keeps around for
+/// [`KiwiEngine::history`](crate::KiwiEngine::history) and
+/// [`KiwiEngine::get_at`](crate::KiwiEngine::get_at).
+///
+/// A version is kept if it's one of the `versions` newest ones of its key, or if it was written
+/// less than `age` ago. The current value of a key is always kept. Older versions of a key are
+/// dropped together with the newest one that isn't kept, so that what's left has no gaps.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Retention {
+    pub versions: Option<usize>,
+    pub age: Option<Duration>,
+}
+
+impl Retention {
+    /// Whether to keep a version written at `timestamp` that has `newer` versions written after it.
+    pub(crate) fn keeps(&self, newer: usize, timestamp: u64) -> bool {
+        self.versions.is_some_and(|versions| newer < versions)
+            || self
+                .age
+                .is_some_and(|age| expiry::now().saturating_sub(timestamp) < age.as_millis() as u64)
+    }
+}
+
+/// Version of a key, returned by [`KiwiEngine::history`](crate::KiwiEngine::history).
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyVersion<V> {
+    /// Sequence number of the write, every write to the store gets a higher one than the last.
+    pub seq: u64,
+    /// When the write happened, the Unix epoch if the write predates timestamps.
+    pub timestamp: SystemTime,
+    /// Value written, `None` for a remove.
+    pub value: Option<V>,
+}
+
+impl<V> KeyVersion<V> {
+    pub(crate) fn new(seq: u64, timestamp: u64, value: Option<V>) -> Self {
+        KeyVersion {
+            seq,
+            timestamp: UNIX_EPOCH + Duration::from_millis(timestamp),
+            value,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Replay of writes from the log, see [`KiwiStore::changes_since`](crate::KiwiStore::changes_since).
+use super::read_exact_at;
+use crate::store::record::{self, Decoded};
+use crate::store::watch;
+use crate::store::ChangeEvent;
+use crate::Result;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::sync::Arc;
+use std::vec;
+
+/// Iterator over writes made after a sequence number, in the order they were made, returned by
+/// [`KiwiStore::changes_since`](crate::KiwiStore::changes_since).
+///
+/// Replays the segments as they were when it was created and keeps them open, so compaction
+/// running in the meantime doesn't pull them away. Later writes aren't included, and neither are
+/// keys expiring. Iteration stops after the first error.
+pub struct Changes {
+    namespace: u32,
+    after: u64,
+    segments: vec::IntoIter<(Arc<File>, u64)>,
+    reader: Option<BufReader<SegmentReader>>,
+    /// Changes of the last record read, a batch makes several.
+    pending: VecDeque<ChangeEvent>,
+}
+
+impl Changes {
+    /// Replay writes to `namespace` from `segments` with their lengths, oldest first, skipping
+    /// writes up to `after`.
+    pub(super) fn new(namespace: u32, after: u64, segments: Vec<(Arc<File>, u64)>) -> Self {
+        Changes {
+            namespace,
+            after,
+            segments: segments.into_iter(),
+            reader: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn read_record(&mut self) -> Option<Result<()>> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => {
+                let (file, len) = self.segments.next()?;
+                let offset = record::MAGIC.len() as u64;
+                self.reader
+                    .insert(BufReader::new(SegmentReader { file, offset, len }))
+            }
+        };
+        let offset = reader.get_ref().offset - reader.buffer().len() as u64;
+        let end = reader.get_ref().len;
+        match record::decode(reader, offset, end) {
+            Ok(Some(Decoded {
+                command,
+                namespace,
+                stamp,
+                ..
+            })) => {
+                if namespace != self.namespace {
+                    return Some(Ok(()));
+                }
+                let after = self.after;
+                self.pending.extend(
+                    watch::changes(command, stamp.seq)
+                        .into_iter()
+                        .filter(|event| event.seq() > after),
+                );
+                Some(Ok(()))
+            }
+            Ok(None) => {
+                self.reader = None;
+                Some(Ok(()))
+            }
+            Err(error) => {
+                self.reader = None;
+                self.segments = Vec::new().into_iter();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if let Err(error) = self.read_record()? {
+                return Some(Err(error));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Changes")
+            .field("namespace", &self.namespace)
+            .field("after", &self.after)
+            .field("segments_left", &self.segments.len())
+            .finish()
+    }
+}
+
+/// Reads a segment up to `len` without moving the cursor shared with other handles of the file.
+struct SegmentReader {
+    file: Arc<File>,
+    offset: u64,
+    len: u64,
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let left = self.len.saturating_sub(self.offset);
+        let count = left.min(buffer.len() as u64) as usize;
+        read_exact_at(&self.file, &mut buffer[..count], self.offset)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
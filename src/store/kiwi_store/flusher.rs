@@ -0,0 +1,107 @@
+//! Background thread flushing the active segment to disk, see [`Durability`].
+use super::Durability;
+
+use log::error;
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Debug)]
+struct State {
+    /// Handle to the segment currently being appended to.
+    file: Arc<File>,
+    /// Whether a writer asked for a flush since the last one.
+    requested: bool,
+    shutdown: bool,
+}
+
+/// Runs `fsync` on the active segment either on request or at a fixed interval.
+///
+/// Dropping it stops the thread after a final flush.
+#[derive(Debug)]
+pub(super) struct Flusher {
+    state: Arc<(Mutex<State>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// Start flushing `file` in the background, returns `None` for policies that don't need it.
+    pub(super) fn start(durability: Durability, file: File) -> Option<Self> {
+        let interval = match durability {
+            Durability::Interval(interval) => Some(interval),
+            Durability::EveryN(_) => None,
+            Durability::Always | Durability::Never => return None,
+        };
+
+        let state = Arc::new((
+            Mutex::new(State {
+                file: Arc::new(file),
+                requested: false,
+                shutdown: false,
+            }),
+            Condvar::new(),
+        ));
+        let thread_state = Arc::clone(&state);
+        let handle = thread::spawn(move || run(&thread_state, interval));
+
+        Some(Flusher {
+            state,
+            handle: Some(handle),
+        })
+    }
+
+    /// Ask for a flush without waiting for it.
+    pub(super) fn request(&self) {
+        let (state, wake_up) = &*self.state;
+        state.lock().expect("error acquiring lock").requested = true;
+        wake_up.notify_one();
+    }
+
+    /// Point the flusher at a new active segment.
+    pub(super) fn replace_file(&self, file: File) {
+        let (state, _) = &*self.state;
+        state.lock().expect("error acquiring lock").file = Arc::new(file);
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let (state, wake_up) = &*self.state;
+        state.lock().expect("error acquiring lock").shutdown = true;
+        wake_up.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(state: &(Mutex<State>, Condvar), interval: Option<Duration>) {
+    let (state, wake_up) = state;
+    loop {
+        let mut guard = state.lock().expect("error acquiring lock");
+        guard = match interval {
+            Some(interval) => {
+                wake_up
+                    .wait_timeout_while(guard, interval, |state| !state.shutdown)
+                    .expect("error acquiring lock")
+                    .0
+            }
+            None => wake_up
+                .wait_while(guard, |state| !state.requested && !state.shutdown)
+                .expect("error acquiring lock"),
+        };
+        guard.requested = false;
+        let shutdown = guard.shutdown;
+        // don't hold the lock while syncing, writers need it to request the next flush
+        let file = Arc::clone(&guard.file);
+        drop(guard);
+
+        if let Err(error) = file.sync_data() {
+            error!("unable to flush log: {}", error);
+        }
+        if shutdown {
+            break;
+        }
+    }
+}
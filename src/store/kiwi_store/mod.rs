@@ -0,0 +1,1666 @@
+mod changes;
+mod flusher;
+mod namespaces;
+mod snapshot;
+mod writer;
+
+pub use self::changes::Changes;
+use self::flusher::Flusher;
+use self::namespaces::Namespaces;
+pub(super) use self::namespaces::{load_names, save_names};
+pub use self::snapshot::KiwiStoreSnapshot;
+use self::writer::Writer;
+use crate::store::counter;
+use crate::store::expiry::{self, Sweeper};
+use crate::store::hint::{self, HintEntry};
+use crate::store::record::{self, Decoded, Stamp, DEFAULT_NAMESPACE};
+use crate::store::watch::Watchers;
+use crate::store::Command;
+use crate::store::{
+    BytesIter, CompareAndSwapError, CompareAndSwapResult, KeyVersion, KiwiEngine, Retention,
+    Watcher, WriteBatch,
+};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use crate::{Error, Result};
+
+use crossbeam_skiplist::map::Entry;
+use crossbeam_skiplist::{SkipMap, SkipSet};
+use log::{error, info, warn};
+use memmap2::Mmap;
+use std::collections::{btree_map, BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::time::Duration;
+use std::vec;
+
+/// Name of the single-file log used before the log was split into segments.
+const LEGACY_LOG: &str = "kvs.db";
+
+/// Tuning knobs for [`KiwiStore::open_with_options`].
+#[derive(Debug, Clone)]
+pub struct KiwiStoreOptions {
+    /// Size in bytes after which the active segment is closed and a new one is started.
+    pub max_segment_size: u64,
+    /// Fraction of the log taken by overwritten and removed records that triggers compaction.
+    pub compaction_garbage_ratio: f64,
+    /// Log size in bytes below which compaction never runs, whatever the garbage ratio.
+    pub compaction_min_size: u64,
+    /// How values are read from the log.
+    pub read_mode: ReadMode,
+    /// When writes are flushed to disk.
+    pub durability: Durability,
+    /// How often expired keys are removed from the index in the background, `None` leaves it to
+    /// writes and compaction.
+    pub sweep_interval: Option<Duration>,
+    /// Overwritten and removed versions of keys kept by compaction for [`KiwiEngine::history`],
+    /// `None` keeps current values only. Retained versions are also indexed in memory.
+    pub retention: Option<Retention>,
+}
+
+/// When [`KiwiStore`] flushes writes to disk with `fsync`.
+///
+/// Writes that weren't flushed yet survive a crash of the process, but not of the machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Flush every write before returning from `set` or `remove`.
+    Always,
+    /// Flush on a background thread after every `n` writes.
+    EveryN(u64),
+    /// Flush on a background thread at a fixed interval.
+    Interval(Duration),
+    /// Leave flushing to the operating system.
+    Never,
+}
+
+/// How [`KiwiStore`] reads values from the log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadMode {
+    /// Positional reads on open file handles.
+    Positional,
+    /// Memory-map immutable segments and slice values out of the mapping, saving a syscall per
+    /// `get` at the cost of address space.
+    Mmap,
+}
+
+impl Default for KiwiStoreOptions {
+    fn default() -> Self {
+        KiwiStoreOptions {
+            max_segment_size: 1024 * 1024,
+            compaction_garbage_ratio: 0.5,
+            compaction_min_size: 1024 * 1024,
+            read_mode: ReadMode::Positional,
+            durability: Durability::Never,
+            sweep_interval: Some(expiry::SWEEP_INTERVAL),
+            retention: None,
+        }
+    }
+}
+
+/// Location of a record in the log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    segment: u64,
+    offset: u64,
+    len: u64,
+    expires_at: Option<u64>,
+    stamp: Stamp,
+}
+
+impl Position {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(expiry::is_expired)
+    }
+}
+
+/// In-memory index of a namespace in the log, keeps track of how many bytes of the log are still live.
+///
+/// Readers look keys up concurrently without any lock, it's modified only by the thread holding
+/// [`KiwiStoreInner`], see [`Writer`].
+#[derive(Debug, Default)]
+struct Index {
+    /// Overwrites update an entry in place, replacing it would make the key briefly missing.
+    positions: SkipMap<Vec<u8>, RwLock<Position>>,
+    live_bytes: AtomicU64,
+    /// Keys that have an expiry, ordered by it, so that expired ones are found without a full scan.
+    expiries: SkipSet<(u64, Vec<u8>)>,
+    /// Versions of every key, oldest first, tracked only with [`KiwiStoreOptions::retention`].
+    history: Option<SkipMap<Vec<u8>, Mutex<Vec<Revision>>>>,
+}
+
+/// Version of a key in the history, see [`Index::history`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Revision {
+    position: Position,
+    /// Whether it's the record of a remove.
+    removed: bool,
+}
+
+impl Index {
+    fn new(retention: Option<Retention>) -> Self {
+        Index {
+            history: retention.map(|_| SkipMap::new()),
+            ..Index::default()
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Position> {
+        self.positions.get(key).map(|entry| read_position(&entry))
+    }
+
+    /// Like [`Index::get`], but treats expired keys as missing.
+    fn get_live(&self, key: &[u8]) -> Option<Position> {
+        self.get(key).filter(|position| !position.is_expired())
+    }
+
+    /// Version of `key` for transactions, the sequence number of its last write or zero if it's
+    /// missing or expired.
+    fn version(&self, key: &[u8]) -> u64 {
+        self.get_live(key).map_or(0, |position| position.stamp.seq)
+    }
+
+    fn live_bytes(&self) -> u64 {
+        self.live_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Only the thread holding [`KiwiStoreInner`] modifies the index, so an existing entry can't
+    /// be removed between the lookup and the update.
+    fn insert(&self, key: Vec<u8>, position: Position) {
+        self.live_bytes.fetch_add(position.len, Ordering::SeqCst);
+        if let Some(old) = self.get(&key) {
+            self.live_bytes.fetch_sub(old.len, Ordering::SeqCst);
+            self.forget_expiry(&key, old);
+        }
+        if let Some(expires_at) = position.expires_at {
+            self.expiries.insert((expires_at, key.clone()));
+        }
+        self.remember(
+            &key,
+            Revision {
+                position,
+                removed: false,
+            },
+        );
+        match self.positions.get(&key) {
+            Some(entry) => *entry.value().write().expect("error acquiring lock") = position,
+            None => {
+                self.positions.insert(key, RwLock::new(position));
+            }
+        }
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<Position> {
+        let old = read_position(&self.positions.remove(key)?);
+        self.live_bytes.fetch_sub(old.len, Ordering::SeqCst);
+        self.forget_expiry(key, old);
+        Some(old)
+    }
+
+    fn forget_expiry(&self, key: &[u8], old: Position) {
+        if let Some(expires_at) = old.expires_at {
+            self.expiries.remove(&(expires_at, key.to_vec()));
+        }
+    }
+
+    /// Remove all expired keys, returns how many there were.
+    fn reap_expired(&self) -> usize {
+        let mut reaped = 0;
+        while let Some(entry) = self.expiries.front() {
+            let (expires_at, key) = entry.value().clone();
+            if !expiry::is_expired(expires_at) {
+                break;
+            }
+            entry.remove();
+            if self.get(&key).and_then(|position| position.expires_at) == Some(expires_at) {
+                self.remove(&key);
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Keys in `range`, ordered.
+    fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Vec<Vec<u8>> {
+        self.positions
+            .range(range)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Keys starting with `prefix`, ordered.
+    fn prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        self.positions
+            .range(prefix.to_vec()..)
+            .take_while(|entry| entry.key().starts_with(prefix))
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Point `key` and its version in the history at `new` if they are still at `old`, the record
+    /// keeps its length and stamp.
+    fn relocate(&self, key: &[u8], old: Position, new: Position) {
+        if let Some(entry) = self.positions.get(key) {
+            let mut position = entry.value().write().expect("error acquiring lock");
+            if *position == old {
+                *position = new;
+            }
+        }
+        if let Some(entry) = self.history.as_ref().and_then(|history| history.get(key)) {
+            let mut revisions = entry.value().lock().expect("error acquiring lock");
+            if let Some(revision) = revisions
+                .iter_mut()
+                .find(|revision| revision.position == old)
+            {
+                revision.position = new;
+            }
+        }
+    }
+
+    /// Add a version of `key` to its history, if history is tracked.
+    fn remember(&self, key: &[u8], revision: Revision) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return,
+        };
+        match history.get(key) {
+            Some(entry) => entry
+                .value()
+                .lock()
+                .expect("error acquiring lock")
+                .push(revision),
+            None => {
+                history.insert(key.to_vec(), Mutex::new(vec![revision]));
+            }
+        }
+    }
+
+    /// Versions of `key`, oldest first, `None` if history isn't tracked.
+    fn revisions(&self, key: &[u8]) -> Option<Vec<Revision>> {
+        let history = self.history.as_ref()?;
+        Some(match history.get(key) {
+            Some(entry) => entry.value().lock().expect("error acquiring lock").clone(),
+            None => Vec::new(),
+        })
+    }
+
+    /// Position of the version of `key` written with sequence number `seq`, if it's still known.
+    fn locate(&self, key: &[u8], seq: u64) -> Option<Position> {
+        match self.revisions(key) {
+            Some(revisions) => revisions
+                .into_iter()
+                .find(|revision| revision.position.stamp.seq == seq)
+                .map(|revision| revision.position),
+            None => self.get(key).filter(|position| position.stamp.seq == seq),
+        }
+    }
+
+    /// Versions to keep in a compaction according to `retention`, together with their total size
+    /// not counting current values.
+    ///
+    /// Versions of a key are kept from the newest one back to the first one `retention` doesn't
+    /// keep, so that no version in between goes missing.
+    fn retained(&self, retention: &Retention) -> (Vec<(Vec<u8>, Revision)>, u64) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return (Vec::new(), 0),
+        };
+        let mut retained = Vec::new();
+        let mut retained_bytes = 0;
+        for entry in history.iter() {
+            let current = self.get(entry.key());
+            let revisions = entry.value().lock().expect("error acquiring lock");
+            let kept = revisions
+                .iter()
+                .rev()
+                .enumerate()
+                .take_while(|&(newer, revision)| {
+                    Some(revision.position) == current
+                        || retention.keeps(newer, revision.position.stamp.timestamp)
+                })
+                .count();
+            for &revision in &revisions[revisions.len() - kept..] {
+                if Some(revision.position) != current {
+                    retained_bytes += revision.position.len;
+                }
+                retained.push((entry.key().clone(), revision));
+            }
+        }
+        (retained, retained_bytes)
+    }
+
+    /// Forget versions left in segments older than `segment`, compaction didn't keep them.
+    fn prune_history(&self, segment: u64) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return,
+        };
+        for entry in history.iter() {
+            let mut revisions = entry.value().lock().expect("error acquiring lock");
+            revisions.retain(|revision| revision.position.segment >= segment);
+            if revisions.is_empty() {
+                entry.remove();
+            }
+        }
+    }
+}
+
+fn read_position(entry: &Entry<Vec<u8>, RwLock<Position>>) -> Position {
+    *entry.value().read().expect("error acquiring lock")
+}
+
+/// Open read handles to segments, shared by all threads.
+///
+/// Records are read with positional reads, so a single handle serves any number of concurrent
+/// readers and a `get` costs no `open`/`seek` syscalls once its segment has been read from.
+/// With [`ReadMode::Mmap`] immutable segments are memory-mapped instead and records are sliced
+/// straight out of the mapping.
+#[derive(Debug)]
+struct Readers {
+    dir: PathBuf,
+    mode: ReadMode,
+    /// Segment that is still being appended to, it's never mapped.
+    active_segment: AtomicU64,
+    files: SkipMap<u64, Arc<File>>,
+    maps: SkipMap<u64, Arc<Mmap>>,
+}
+
+impl Readers {
+    fn new(dir: PathBuf, mode: ReadMode) -> Self {
+        Readers {
+            dir,
+            mode,
+            active_segment: AtomicU64::new(0),
+            files: SkipMap::new(),
+            maps: SkipMap::new(),
+        }
+    }
+
+    /// Read raw bytes of the record at `position`.
+    fn read(&self, position: Position) -> Result<Vec<u8>> {
+        self.with_record(position, |record| Ok(record.to_vec()))
+    }
+
+    /// Call `f` with raw bytes of the record at `position`, without copying them if possible.
+    fn with_record<T>(&self, position: Position, f: impl FnOnce(&[u8]) -> Result<T>) -> Result<T> {
+        if self.mode == ReadMode::Mmap
+            && position.segment != self.active_segment.load(Ordering::SeqCst)
+        {
+            let map = self.map(position.segment)?;
+            let start = position.offset as usize;
+            return match map.get(start..start + position.len as usize) {
+                Some(record) => f(record),
+                None => Err(Error::Offset(format!(
+                    "record at offset {} is out of segment {}",
+                    position.offset, position.segment
+                ))),
+            };
+        }
+
+        let file = self.file(position.segment)?;
+        let mut buffer = vec![0u8; position.len as usize];
+        read_exact_at(&file, &mut buffer, position.offset)?;
+        f(&buffer)
+    }
+
+    fn file(&self, segment: u64) -> Result<Arc<File>> {
+        cached(&self.files, segment, || {
+            Ok(File::open(segment_path(&self.dir, segment))?)
+        })
+    }
+
+    fn map(&self, segment: u64) -> Result<Arc<Mmap>> {
+        cached(&self.maps, segment, || {
+            let file = self.file(segment)?;
+            // SAFETY: only immutable segments are mapped, they are never written to or truncated,
+            // only removed once no longer referenced by the index, which keeps the mapping valid
+            Ok(unsafe { Mmap::map(&*file)? })
+        })
+    }
+
+    /// Note that `segment` is now the one being appended to.
+    fn set_active(&self, segment: u64) {
+        self.active_segment.store(segment, Ordering::SeqCst);
+    }
+
+    /// Drop handles of all segments older than `segment`, they are about to be removed.
+    ///
+    /// Readers in the middle of a read keep their own reference until they are done. This also
+    /// drops handles that a slow reader reopened after an earlier call.
+    fn close_before(&self, segment: u64) {
+        for entry in self.maps.range(..segment) {
+            entry.remove();
+        }
+        for entry in self.files.range(..segment) {
+            entry.remove();
+        }
+    }
+}
+
+/// Get value for `segment` from `cache`, creating it with `open` on first use.
+fn cached<T: Send + Sync + 'static>(
+    cache: &SkipMap<u64, Arc<T>>,
+    segment: u64,
+    open: impl FnOnce() -> Result<T>,
+) -> Result<Arc<T>> {
+    if let Some(entry) = cache.get(&segment) {
+        return Ok(Arc::clone(entry.value()));
+    }
+    // another reader might have opened it in the meantime, keep just one of them
+    let value = Arc::new(open()?);
+    Ok(Arc::clone(cache.get_or_insert(segment, value).value()))
+}
+
+/// The log is split into segments named `<id>.log`, only the one with the highest id is written to,
+/// all the other ones are immutable.
+#[derive(Debug)]
+pub struct KiwiStoreInner {
+    dir: PathBuf,
+    options: KiwiStoreOptions,
+    namespaces: Arc<Namespaces>,
+    readers: Arc<Readers>,
+    write_log: File,
+    /// Flushes `write_log` in the background for [`Durability::EveryN`] and [`Durability::Interval`].
+    flusher: Option<Flusher>,
+    /// Writes since the last flush was requested, for [`Durability::EveryN`].
+    unsynced_writes: u64,
+    active_segment: u64,
+    /// Size of every segment, including the active one.
+    segments: BTreeMap<u64, u64>,
+    /// Whether a background compaction is in progress.
+    compacting: bool,
+    /// Sequence number of the next write.
+    next_seq: u64,
+    /// Bytes of overwritten and removed versions kept by the last compaction, see
+    /// [`KiwiStoreOptions::retention`].
+    retained_bytes: u64,
+    /// Subscribers to changes, fed by every write.
+    watchers: Watchers,
+}
+
+impl KiwiStoreInner {
+    /// Open KvStore at a specified location, loading its indexes into `namespaces`.
+    fn open(
+        dir: PathBuf,
+        options: KiwiStoreOptions,
+        namespaces: Arc<Namespaces>,
+        readers: Arc<Readers>,
+    ) -> Result<Self> {
+        let legacy_path = dir.join(LEGACY_LOG);
+        if legacy_path.exists() {
+            if record::is_legacy(&legacy_path)? {
+                info!("migrating {} to binary log format", legacy_path.display());
+                record::migrate_legacy(&legacy_path, 1)?;
+            }
+            // new stores start at segment 1, so the legacy log is always the oldest one
+            fs::rename(&legacy_path, segment_path(&dir, 0))?;
+        }
+
+        remove_unfinished_files(&dir)?;
+
+        let mut segments = BTreeMap::new();
+        let mut next_seq = 1;
+        let ids = list_segments(&dir)?;
+        for (index, &id) in ids.iter().enumerate() {
+            let is_last = index + 1 == ids.len();
+            let (len, segment_next_seq) = load_segment(&dir, id, is_last, &namespaces)?;
+            segments.insert(id, len);
+            next_seq = next_seq.max(segment_next_seq);
+        }
+
+        let active_segment = match ids.last() {
+            Some(&id) => id,
+            None => {
+                record::create_segment(&segment_path(&dir, 1))?;
+                segments.insert(1, record::MAGIC.len() as u64);
+                1
+            }
+        };
+        let write_log = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&dir, active_segment))?;
+        let flusher = Flusher::start(options.durability, write_log.try_clone()?);
+        readers.set_active(active_segment);
+
+        Ok(KiwiStoreInner {
+            dir,
+            options,
+            namespaces,
+            readers,
+            write_log,
+            flusher,
+            unsynced_writes: 0,
+            active_segment,
+            segments,
+            compacting: false,
+            next_seq,
+            retained_bytes: 0,
+            watchers: Watchers::default(),
+        })
+    }
+
+    /// Append `commands` on keys of the namespaces they come with to the log with a single write,
+    /// flushed at most once.
+    ///
+    /// Returns outcome of every command, removing a missing key fails only that command.
+    /// Fails as a whole if the batch couldn't be written, the index is left untouched then.
+    fn write_batch(&mut self, commands: Vec<(u32, Command)>) -> Result<Vec<Result<()>>> {
+        let mut buffer = Vec::new();
+        let mut outcomes = Vec::with_capacity(commands.len());
+        let mut written = Vec::with_capacity(commands.len());
+        // keys set or removed by earlier commands of this batch
+        let mut pending: HashMap<(u32, Vec<u8>), bool> = HashMap::new();
+        // sequence numbers taken by earlier commands of this batch
+        let mut seq_count = 0;
+        // expired keys are reaped lazily, their records become garbage for the next compaction
+        self.sweep_expired();
+
+        for (namespace, command) in commands {
+            if let Command::Remove(key) = &command {
+                let exists = match pending.get(&(namespace, key.clone())) {
+                    Some(&exists) => exists,
+                    None => self.namespaces.index(namespace).get_live(key).is_some(),
+                };
+                if !exists {
+                    outcomes.push(Err(Error::NoKey(String::from("Key not found"))));
+                    continue;
+                }
+            }
+            match &command {
+                Command::Set((key, _, _)) => pending.insert((namespace, key.clone()), true),
+                Command::Remove(key) => pending.insert((namespace, key.clone()), false),
+                Command::Batch(commands) => {
+                    for command in commands {
+                        match command {
+                            Command::Set((key, _, _)) => {
+                                pending.insert((namespace, key.clone()), true)
+                            }
+                            Command::Remove(key) => pending.insert((namespace, key.clone()), false),
+                            Command::Batch(_) => unreachable!("batches are never nested"),
+                        };
+                    }
+                    None
+                }
+            };
+
+            let stamp = Stamp::now(self.next_seq + seq_count);
+            seq_count += record::seq_count(&command);
+            let record = record::encode(&command, namespace, stamp);
+            let (offset, len) = (buffer.len() as u64, record.len() as u64);
+            written.push((namespace, command, stamp, offset, len));
+            buffer.extend_from_slice(&record);
+            outcomes.push(Ok(()));
+        }
+
+        if written.is_empty() {
+            return Ok(outcomes);
+        }
+        let (segment, batch_offset) = self.append(&buffer, written.len() as u64)?;
+        self.next_seq += seq_count;
+
+        for (namespace, command, stamp, offset, len) in written {
+            let position = Position {
+                segment,
+                offset: batch_offset + offset,
+                len,
+                expires_at: None,
+                stamp,
+            };
+            if !self.watchers.is_empty() {
+                self.watchers.publish(namespace, &command, stamp.seq);
+            }
+            load_command(&self.namespaces, namespace, command, position);
+        }
+        Ok(outcomes)
+    }
+
+    /// Write `new` if the live value of `key` in `namespace` is `expected`, see
+    /// [`KiwiEngine::compare_and_swap`].
+    fn compare_and_swap(
+        &mut self,
+        namespace: u32,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        let store = self.namespaces.index(namespace);
+        let current = match store.get_live(&key) {
+            Some(position) => read_value(&store, &self.readers, &key, position)?,
+            None => None,
+        };
+        if current != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+
+        let command = match new {
+            Some(value) => Command::Set((key, value, None)),
+            None if current.is_some() => Command::Remove(key),
+            None => return Ok(Ok(())),
+        };
+        for outcome in self.write_batch(vec![(namespace, command)])? {
+            outcome?;
+        }
+        Ok(Ok(()))
+    }
+
+    /// Freeze live keys of the index of `namespace` and pin the segments they are in.
+    ///
+    /// Copies the whole index of the namespace, writes have to wait meanwhile.
+    fn snapshot(&self, namespace: u32) -> Result<KiwiStoreSnapshot> {
+        let mut positions = BTreeMap::new();
+        let mut segments = BTreeMap::new();
+        for entry in self.namespaces.index(namespace).positions.iter() {
+            let position = read_position(&entry);
+            if position.is_expired() {
+                continue;
+            }
+            if let btree_map::Entry::Vacant(entry) = segments.entry(position.segment) {
+                entry.insert(self.readers.file(position.segment)?);
+            }
+            positions.insert(entry.key().clone(), position);
+        }
+        Ok(KiwiStoreSnapshot::new(positions, segments))
+    }
+
+    /// Pin all segments for a replay of writes to `namespace` made after `seq`, see
+    /// [`KiwiStore::changes_since`].
+    fn changes_since(&self, namespace: u32, seq: u64) -> Result<Changes> {
+        // writes from the horizon on are all in the log, older ones may have been compacted away
+        if seq.saturating_add(1) < self.namespaces.horizon() {
+            return Err(Error::HistoryTruncated(seq));
+        }
+        let segments = self
+            .segments
+            .iter()
+            .map(|(&id, &len)| Ok((self.readers.file(id)?, len)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Changes::new(namespace, seq, segments))
+    }
+
+    /// Apply `commands` as a batch if no key of `namespace` in `reads` has changed, see
+    /// [`Transaction::commit`](crate::Transaction::commit).
+    fn commit(
+        &mut self,
+        namespace: u32,
+        reads: Vec<(Vec<u8>, u64)>,
+        commands: Vec<Command>,
+    ) -> Result<()> {
+        let store = self.namespaces.index(namespace);
+        for (key, version) in reads {
+            if store.version(&key) != version {
+                return Err(Error::Conflict(key));
+            }
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
+        for outcome in self.write_batch(vec![(namespace, Command::Batch(commands))])? {
+            outcome?;
+        }
+        Ok(())
+    }
+
+    /// Add `delta` to the counter at `key` in `namespace`, see [`KiwiEngine::incr_by`].
+    fn incr_by(&mut self, namespace: u32, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let store = self.namespaces.index(namespace);
+        let (current, expires_at) = match store.get_live(&key) {
+            Some(position) => match read_value(&store, &self.readers, &key, position)? {
+                Some(value) => (Some(value), position.expires_at),
+                None => (None, None),
+            },
+            None => (None, None),
+        };
+        let (count, value) = counter::increment(current.as_deref(), delta)?;
+        let command = Command::Set((key, value, expires_at));
+        for outcome in self.write_batch(vec![(namespace, command)])? {
+            outcome?;
+        }
+        Ok(count)
+    }
+
+    /// Write encoded `records` at the end of the active segment, rolling over to a new one if needed.
+    /// Returns the segment and offset they were written at.
+    ///
+    /// All records always end up in the same segment, which can therefore exceed
+    /// [`KiwiStoreOptions::max_segment_size`] by a single batch.
+    fn append(&mut self, records: &[u8], count: u64) -> Result<(u64, u64)> {
+        let active_len = self.segments[&self.active_segment];
+        if active_len > record::MAGIC.len() as u64
+            && active_len + records.len() as u64 > self.options.max_segment_size
+        {
+            self.roll_over(self.active_segment + 1)?;
+        }
+
+        let offset = self.segments[&self.active_segment];
+        if let Err(error) = self.write_log.write_all(records) {
+            // don't leave a partial batch behind for the next one to be appended after
+            let _ = self.write_log.set_len(offset);
+            return Err(error.into());
+        }
+        self.sync_written(count)?;
+        *self
+            .segments
+            .get_mut(&self.active_segment)
+            .expect("active segment is tracked") += records.len() as u64;
+
+        Ok((self.active_segment, offset))
+    }
+
+    /// Flush `count` written records according to [`KiwiStoreOptions::durability`].
+    fn sync_written(&mut self, count: u64) -> Result<()> {
+        match self.options.durability {
+            Durability::Always => self.write_log.sync_data()?,
+            Durability::EveryN(n) => {
+                self.unsynced_writes += count;
+                if self.unsynced_writes >= n {
+                    self.unsynced_writes = 0;
+                    if let Some(flusher) = &self.flusher {
+                        flusher.request();
+                    }
+                }
+            }
+            Durability::Interval(_) | Durability::Never => {}
+        }
+        Ok(())
+    }
+
+    /// Remove expired keys from the indexes of all namespaces, returns how many there were.
+    fn sweep_expired(&mut self) -> usize {
+        self.namespaces
+            .indexes()
+            .iter()
+            .map(|(_, store)| store.reap_expired())
+            .sum()
+    }
+
+    /// Bytes taken by current values of all namespaces.
+    fn live_bytes(&self) -> u64 {
+        self.namespaces
+            .indexes()
+            .iter()
+            .map(|(_, store)| store.live_bytes())
+            .sum()
+    }
+
+    /// Bytes taken by overwritten and removed records, not counting the ones the last compaction
+    /// kept as history.
+    fn dead_bytes(&self) -> u64 {
+        let headers = self.segments.len() as u64 * record::MAGIC.len() as u64;
+        (self.segments.values().sum::<u64>() - headers)
+            .saturating_sub(self.live_bytes() + self.retained_bytes)
+    }
+
+    /// Close the active segment and start writing to a new, empty one.
+    fn roll_over(&mut self, id: u64) -> Result<()> {
+        let durable = self.options.durability != Durability::Never;
+        if durable {
+            // whatever is left unflushed would never be flushed by the background thread
+            self.write_log.sync_data()?;
+        }
+
+        self.write_log = record::create_segment(&segment_path(&self.dir, id))?;
+        if durable {
+            sync_dir(&self.dir)?;
+        }
+        if let Some(flusher) = &self.flusher {
+            flusher.replace_file(self.write_log.try_clone()?);
+        }
+        self.segments.insert(id, record::MAGIC.len() as u64);
+        self.active_segment = id;
+        self.readers.set_active(id);
+        Ok(())
+    }
+
+    /// Close the active segment and take a snapshot of the index for [`Compaction::run`].
+    ///
+    /// Returns `None` if the log is still small or mostly live, or another compaction is already
+    /// running.
+    /// New writes go to a segment newer than the merged one, so that replaying segments in order of
+    /// their ids always yields the latest values.
+    fn start_compaction(&mut self) -> Result<Option<Compaction>> {
+        let dead_bytes = self.dead_bytes();
+        let total_bytes = self.live_bytes() + dead_bytes;
+        if self.compacting
+            || total_bytes < self.options.compaction_min_size
+            || (dead_bytes as f64) < self.options.compaction_garbage_ratio * total_bytes as f64
+        {
+            return Ok(None);
+        }
+
+        let segment = self.active_segment + 1;
+        self.roll_over(segment + 1)?;
+        self.compacting = true;
+
+        let mut entries = Vec::new();
+        let mut retained_bytes = 0;
+        for (namespace, store) in self.namespaces.indexes() {
+            match &self.options.retention {
+                Some(retention) => {
+                    let (retained, bytes) = store.retained(retention);
+                    let retained = retained
+                        .into_iter()
+                        .map(|(key, revision)| (namespace, key, revision));
+                    entries.extend(retained);
+                    retained_bytes += bytes;
+                }
+                None => {
+                    let live = store.positions.iter().map(|entry| {
+                        let revision = Revision {
+                            position: read_position(&entry),
+                            removed: false,
+                        };
+                        (namespace, entry.key().clone(), revision)
+                    });
+                    entries.extend(live);
+                }
+            }
+        }
+        Ok(Some(Compaction {
+            dir: self.dir.clone(),
+            readers: Arc::clone(&self.readers),
+            segment,
+            horizon: self.next_seq,
+            entries,
+            retained_bytes,
+        }))
+    }
+
+    /// Point the index at the merged segment and drop the segments it replaced.
+    ///
+    /// Keys written or removed while the compaction was running keep their newer state.
+    fn finish_compaction(&mut self, result: Result<Compacted>) {
+        self.compacting = false;
+        let compacted = match result {
+            Ok(compacted) => compacted,
+            Err(error) => {
+                error!("compaction failed: {}", error);
+                return;
+            }
+        };
+
+        for (namespace, key, old_position, new_position) in compacted.entries {
+            self.namespaces
+                .index(namespace)
+                .relocate(&key, old_position, new_position);
+        }
+        for (_, store) in self.namespaces.indexes() {
+            store.prune_history(compacted.segment);
+        }
+        self.namespaces.raise_horizon(compacted.horizon);
+        self.retained_bytes = compacted.retained_bytes;
+
+        // merged segments are no longer referenced by the index, a reader that still got a position
+        // in one of them fails to open it and looks the key up again
+        self.readers.close_before(compacted.segment);
+        let stale: Vec<u64> = self
+            .segments
+            .range(..compacted.segment)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in stale {
+            if let Err(error) = fs::remove_file(segment_path(&self.dir, id)) {
+                warn!("unable to remove compacted segment {}: {}", id, error);
+            }
+            let _ = fs::remove_file(hint_path(&self.dir, id));
+            self.segments.remove(&id);
+        }
+        self.segments.insert(compacted.segment, compacted.len);
+    }
+}
+
+/// Snapshot of the index to be merged into a single segment, see [`KiwiStoreInner::start_compaction`].
+struct Compaction {
+    dir: PathBuf,
+    readers: Arc<Readers>,
+    segment: u64,
+    /// Sequence number of the first write not merged, history is complete from there on.
+    horizon: u64,
+    /// Versions of keys to keep with their namespaces, versions of the same key ordered oldest
+    /// first.
+    entries: Vec<(u32, Vec<u8>, Revision)>,
+    retained_bytes: u64,
+}
+
+/// Result of a [`Compaction`], holds old and new position of every merged record.
+struct Compacted {
+    segment: u64,
+    len: u64,
+    horizon: u64,
+    entries: Vec<(u32, Vec<u8>, Position, Position)>,
+    retained_bytes: u64,
+}
+
+impl Compaction {
+    /// Write live records into the new segment. Only touches immutable segments, so it doesn't
+    /// need to hold any lock.
+    fn run(self) -> Result<Compacted> {
+        let tmp_path = segment_path(&self.dir, self.segment).with_extension("log.tmp");
+        let hint_path = hint_path(&self.dir, self.segment);
+        let result = self.write_segment(&tmp_path);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            let _ = fs::remove_file(&hint_path);
+        }
+        result
+    }
+
+    fn write_segment(self, tmp_path: &Path) -> Result<Compacted> {
+        let mut new_log = BufWriter::new(record::create_log(tmp_path)?);
+        let marker = record::marker(self.horizon);
+        new_log.write_all(&marker)?;
+        let mut new_offset = (record::MAGIC.len() + marker.len()) as u64;
+        let mut entries = Vec::with_capacity(self.entries.len());
+        let mut hints = Vec::with_capacity(self.entries.len());
+
+        for (namespace, key, Revision { position, removed }) in self.entries {
+            // copy the record as is, it's already a `Set` or `Remove` with its original stamp
+            let command = self.readers.read(position)?;
+            new_log.write_all(&command)?;
+            let new_position = Position {
+                segment: self.segment,
+                offset: new_offset,
+                ..position
+            };
+            hints.push(HintEntry {
+                key: key.clone(),
+                namespace,
+                offset: new_offset,
+                len: position.len,
+                expires_at: position.expires_at,
+                stamp: position.stamp,
+                removed,
+            });
+            entries.push((namespace, key, position, new_position));
+            new_offset += position.len;
+        }
+        new_log.flush()?;
+        new_log.get_ref().sync_all()?;
+
+        // a hint file without its segment is never read, the other way round it's just replayed
+        let hint_path = hint_path(&self.dir, self.segment);
+        hint::write(&hint_path, new_offset, self.horizon, &hints)?;
+        // merged segments get removed right after, the new one has to be on disk by then
+        sync_dir(&self.dir)?;
+        fs::rename(tmp_path, segment_path(&self.dir, self.segment))?;
+        sync_dir(&self.dir)?;
+
+        Ok(Compacted {
+            segment: self.segment,
+            len: new_offset,
+            horizon: self.horizon,
+            entries,
+            retained_bytes: self.retained_bytes,
+        })
+    }
+}
+
+/// KvStore is a key-value store allowing you store values in-memory with O(log n) lookup time.
+/// # Example
+/// ```
+/// # use std::error::Error;
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// # let some_dir = TempDir::new().unwrap();
+/// use kiwi_store::{KiwiStore, KiwiEngine};
+/// let mut store = KiwiStore::open(some_dir.path())?;
+///
+/// store.set("key1".to_owned(), "value1".to_owned());
+/// assert_eq!(Some("value1".to_owned()), store.get("key1".to_owned())?);
+///
+/// store.remove("key1".to_owned());
+/// assert_eq!(None, store.get("key1".to_owned())?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KiwiStore {
+    /// Id of the namespace of this handle, see [`KiwiEngine::open_tree`].
+    namespace: u32,
+    /// Index of `namespace`.
+    store: Arc<Index>,
+    namespaces: Arc<Namespaces>,
+    readers: Arc<Readers>,
+    // dropped before `writer`, so that the last handle waits for a sweep in progress
+    _sweeper: Option<Arc<Sweeper>>,
+    writer: Arc<Writer>,
+}
+
+impl KiwiStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KiwiStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KiwiStoreOptions) -> Result<Self> {
+        let dir = path.into();
+        let sweep_interval = options.sweep_interval;
+        let namespaces = Arc::new(Namespaces::open(&dir, options.retention)?);
+        let readers = Arc::new(Readers::new(dir.clone(), options.read_mode));
+        let inner =
+            KiwiStoreInner::open(dir, options, Arc::clone(&namespaces), Arc::clone(&readers))?;
+        let writer = Arc::new(Writer::new(Arc::new(Mutex::new(inner)))?);
+
+        let sweeper = sweep_interval.map(|interval| {
+            let writer = Arc::downgrade(&writer);
+            Arc::new(Sweeper::start(interval, move || {
+                match Weak::upgrade(&writer) {
+                    Some(writer) => writer.sweep().map(|_| true),
+                    None => Ok(false),
+                }
+            }))
+        });
+
+        Ok(KiwiStore {
+            namespace: DEFAULT_NAMESPACE,
+            store: namespaces.index(DEFAULT_NAMESPACE),
+            namespaces,
+            readers,
+            _sweeper: sweeper,
+            writer,
+        })
+    }
+
+    /// Replay writes to the namespace of this handle with sequence numbers above `seq` from the
+    /// log, oldest first, see [`Changes`].
+    ///
+    /// `changes_since(0)` starts from the first write. Fails with
+    /// [`Error::HistoryTruncated`] if compaction already merged away some of the writes asked for.
+    pub fn changes_since(&self, seq: u64) -> Result<Changes> {
+        self.writer
+            .exclusive(|inner| inner.changes_since(self.namespace, seq))
+    }
+}
+
+/// Single thread running compactions in the background.
+///
+/// Dropping it waits for the running compaction, so that the store can be safely reopened as soon
+/// as the last [`KiwiStore`] handle is gone.
+#[derive(Debug)]
+struct Compactor {
+    pool: SharedQueueThreadPool,
+    running: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Compactor {
+    fn new() -> Result<Self> {
+        Ok(Compactor {
+            pool: SharedQueueThreadPool::new(1)?,
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+        })
+    }
+
+    /// Start a compaction in the background if the log has grown big enough.
+    ///
+    /// Writes are blocked only while the result gets swapped in, reads aren't blocked at all.
+    fn maybe_compact(
+        &self,
+        store: &Arc<Mutex<KiwiStoreInner>>,
+        inner: &mut KiwiStoreInner,
+    ) -> Result<()> {
+        if let Some(compaction) = inner.start_compaction()? {
+            let store = Arc::clone(store);
+            self.spawn(move || {
+                let result = compaction.run();
+                store
+                    .lock()
+                    .expect("error acquiring lock")
+                    .finish_compaction(result);
+            });
+        }
+        Ok(())
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        *self.running.0.lock().expect("error acquiring lock") += 1;
+        let running = Arc::clone(&self.running);
+        self.pool.spawn(move || {
+            // decrement even if the job panics
+            let _done = Done(running);
+            job();
+        });
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        let (count, finished) = &*self.running;
+        let mut count = count.lock().expect("error acquiring lock");
+        while *count > 0 {
+            count = finished.wait(count).expect("error acquiring lock");
+        }
+    }
+}
+
+struct Done(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        let (count, finished) = &*self.0;
+        *count.lock().expect("error acquiring lock") -= 1;
+        finished.notify_all();
+    }
+}
+
+impl KiwiEngine for KiwiStore {
+    /// Sequence number of the last write to the key.
+    type Version = u64;
+    type Snapshot = KiwiStoreSnapshot;
+
+    /// Set a value. Overrides the value if key is already present
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writer
+            .write(self.namespace, Command::Set((key, value, None)))
+    }
+
+    /// Set a value that expires after `ttl`, the expiry is stored in the log record.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        self.writer
+            .write(self.namespace, Command::Set((key, value, Some(expires_at))))
+    }
+
+    /// Get a value. Doesn't take any lock.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.store.get_live(key) {
+            Some(position) => read_value(&self.store, &self.readers, key, position),
+            None => Ok(None),
+        }
+    }
+
+    /// Time left until `key` expires. Doesn't take any lock.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        Ok(self
+            .store
+            .get_live(key)
+            .and_then(|position| position.expires_at)
+            .map(expiry::remaining))
+    }
+
+    /// Remove a value. If value wasn't present, nothing happens.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.writer.write(self.namespace, Command::Remove(key))
+    }
+
+    /// Compare and swap under the write lock, reads aren't blocked.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        self.writer
+            .exclusive(|inner| inner.compare_and_swap(self.namespace, key, expected, new))
+    }
+
+    /// Increment under the write lock, like [`KiwiEngine::compare_and_swap_bytes`].
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.writer
+            .exclusive(|inner| inner.incr_by(self.namespace, key, delta))
+    }
+
+    /// Apply a batch, written as a single record. Its keys are updated in the index one by one.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.writer
+            .write(self.namespace, Command::Batch(batch.into_commands()))
+    }
+
+    /// Read a value and its version without taking any lock.
+    ///
+    /// If the key is overwritten while being read, the newer value may come with the older version,
+    /// which only makes the commit fail.
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, u64)> {
+        match self.store.get_live(key) {
+            Some(position) => {
+                let value = read_value(&self.store, &self.readers, key, position)?;
+                Ok((value, position.stamp.seq))
+            }
+            None => Ok((None, 0)),
+        }
+    }
+
+    /// Validate and apply a transaction under the write lock.
+    fn commit_transaction(&self, reads: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()> {
+        let commands = batch.into_commands();
+        self.writer
+            .exclusive(|inner| inner.commit(self.namespace, reads, commands))
+    }
+
+    /// Take a snapshot under the write lock, copying the index. Reads aren't blocked.
+    fn snapshot(&self) -> Result<KiwiStoreSnapshot> {
+        self.writer
+            .exclusive(|inner| inner.snapshot(self.namespace))
+    }
+
+    /// Versions of `key` tracked in memory, read from the log. Without
+    /// [`KiwiStoreOptions::retention`] that's the current value only.
+    fn history_bytes(&self, key: &[u8]) -> Result<Vec<KeyVersion<Vec<u8>>>> {
+        let revisions = match self.store.revisions(key) {
+            Some(revisions) => revisions,
+            None => self
+                .store
+                .get_live(key)
+                .map(|position| Revision {
+                    position,
+                    removed: false,
+                })
+                .into_iter()
+                .collect(),
+        };
+        revisions
+            .into_iter()
+            .map(|revision| {
+                let value = read_revision(&self.store, &self.readers, key, revision)?;
+                let Stamp { seq, timestamp } = revision.position.stamp;
+                Ok(KeyVersion::new(seq, timestamp, value))
+            })
+            .collect()
+    }
+
+    /// Read a past value without taking any lock. Without [`KiwiStoreOptions::retention`] only the
+    /// current value can be told.
+    fn get_at_bytes(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let revision = match self.store.revisions(key) {
+            Some(revisions) => revisions
+                .into_iter()
+                .rev()
+                .find(|revision| revision.position.stamp.seq <= seq),
+            None => match self.store.get_live(key) {
+                Some(position) if position.stamp.seq <= seq => Some(Revision {
+                    position,
+                    removed: false,
+                }),
+                _ => return Err(Error::HistoryTruncated(seq)),
+            },
+        };
+        match revision {
+            Some(revision) => read_revision(&self.store, &self.readers, key, revision),
+            None if seq >= self.namespaces.horizon() => Ok(None),
+            None => Err(Error::HistoryTruncated(seq)),
+        }
+    }
+
+    /// Iterate over key-value pairs in `range`. Keys are collected up front, values are read lazily.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter> {
+        Ok(Box::new(Scan {
+            store: Arc::clone(&self.store),
+            readers: Arc::clone(&self.readers),
+            keys: self.store.range(range).into_iter(),
+        }))
+    }
+
+    /// Iterate over key-value pairs with keys starting with `prefix`.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter> {
+        Ok(Box::new(Scan {
+            store: Arc::clone(&self.store),
+            readers: Arc::clone(&self.readers),
+            keys: self.store.prefix(&prefix).into_iter(),
+        }))
+    }
+
+    /// Register a watcher under the write lock, so it sees exactly the writes applied after it.
+    fn watch_bytes(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        self.writer
+            .exclusive(|inner| Ok(inner.watchers.watch(self.namespace, prefix)))
+    }
+
+    /// Handle sharing the log, the background threads and the write lock with this one. The name
+    /// gets its id on first use, records of the namespace carry just the id.
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        let namespace = self.namespaces.id(name)?;
+        Ok(KiwiStore {
+            namespace,
+            store: self.namespaces.index(namespace),
+            ..self.clone()
+        })
+    }
+
+    fn find_tree(&self, name: &str) -> Result<Option<Self>> {
+        Ok(self.namespaces.find_id(name).map(|namespace| KiwiStore {
+            namespace,
+            store: self.namespaces.index(namespace),
+            ..self.clone()
+        }))
+    }
+}
+
+/// Iterator returned by [`KiwiStore`] scans, skips keys removed after the scan started.
+struct Scan {
+    store: Arc<Index>,
+    readers: Arc<Readers>,
+    keys: vec::IntoIter<Vec<u8>>,
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in &mut self.keys {
+            let position = match self.store.get_live(&key) {
+                Some(position) => position,
+                None => continue,
+            };
+            match read_value(&self.store, &self.readers, &key, position) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        None
+    }
+}
+
+/// Read value of `key` found at `position`, returns `None` if the key got removed since.
+fn read_value(
+    store: &Index,
+    readers: &Readers,
+    key: &[u8],
+    mut position: Position,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        match value_from_file(readers, position) {
+            // compaction moved the value and removed its old segment in the meantime
+            Err(error) => match store.get_live(key) {
+                Some(current) if current != position => position = current,
+                Some(_) => return Err(error),
+                None => return Ok(None),
+            },
+            Ok(value) => return Ok(Some(value)),
+        }
+    }
+}
+
+/// Read value of the version of `key` in `revision`, `None` for a remove.
+///
+/// Fails with [`Error::HistoryTruncated`] if compaction dropped the version in the meantime.
+fn read_revision(
+    store: &Index,
+    readers: &Readers,
+    key: &[u8],
+    revision: Revision,
+) -> Result<Option<Vec<u8>>> {
+    if revision.removed {
+        return Ok(None);
+    }
+    let mut position = revision.position;
+    loop {
+        match value_from_file(readers, position) {
+            // compaction moved the value and removed its old segment in the meantime
+            Err(error) => match store.locate(key, position.stamp.seq) {
+                Some(current) if current != position => position = current,
+                Some(_) => return Err(error),
+                None => return Err(Error::HistoryTruncated(position.stamp.seq)),
+            },
+            Ok(value) => return Ok(Some(value)),
+        }
+    }
+}
+
+pub(super) fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.log", id))
+}
+
+pub(super) fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.hint", id))
+}
+
+/// Ids of all segments in `dir`, sorted from oldest to newest.
+pub(super) fn list_segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("log")) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Remove files left behind by a compaction or the creation of a segment that didn't finish before
+/// the process exited.
+fn remove_unfinished_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.to_string_lossy();
+        if name.ends_with(".log.tmp") || name.ends_with(".hint.tmp") {
+            warn!("removing unfinished file {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Load segment into the index from its hint file or by replaying it, returns size of the segment
+/// and the sequence number following the last one in it.
+///
+/// Only the last segment can have been cut short by a crash, an incomplete record at its end gets
+/// truncated. Any other failure to decode a record is an error.
+fn load_segment(dir: &Path, id: u64, is_last: bool, namespaces: &Namespaces) -> Result<(u64, u64)> {
+    let path = segment_path(dir, id);
+    let file = File::open(&path)?;
+    let file_len = file.metadata()?.len();
+    let mut next_seq = 0;
+
+    if let Some((horizon, hints)) = load_hint(dir, id, file_len) {
+        namespaces.raise_horizon(horizon);
+        next_seq = horizon;
+        for entry in hints {
+            let position = Position {
+                segment: id,
+                offset: entry.offset,
+                len: entry.len,
+                expires_at: entry.expires_at,
+                stamp: entry.stamp,
+            };
+            next_seq = next_seq.max(entry.stamp.seq + 1);
+            if entry.removed {
+                let command = Command::Remove(entry.key);
+                load_command(namespaces, entry.namespace, command, position);
+            } else {
+                load_position(&namespaces.index(entry.namespace), entry.key, position);
+            }
+        }
+        return Ok((file_len, next_seq));
+    }
+
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(record::MAGIC.len() as u64))?;
+    let mut current_offset = record::MAGIC.len() as u64;
+
+    loop {
+        let Decoded {
+            command,
+            namespace,
+            stamp,
+            len,
+        } = match record::decode(&mut reader, current_offset, file_len) {
+            Ok(Some(record)) => record,
+            Ok(None) => break, // end of stream
+            Err(error) if is_last && record::is_torn_tail(&error, &path, current_offset)? => {
+                warn!(
+                    "{}: discarding {} bytes of incomplete record at offset {}",
+                    path.display(),
+                    file_len - current_offset,
+                    current_offset
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(current_offset)?;
+                file.sync_all()?;
+                break;
+            }
+            Err(error) => {
+                error!("{}: {}", path.display(), error);
+                return Err(error);
+            }
+        };
+
+        let position = Position {
+            segment: id,
+            offset: current_offset,
+            len,
+            expires_at: None,
+            stamp,
+        };
+        next_seq = next_seq.max(stamp.seq + record::seq_count(&command));
+        load_command(namespaces, namespace, command, position);
+
+        current_offset += len;
+    }
+
+    Ok((current_offset, next_seq))
+}
+
+/// Apply `command` on keys of `namespace` written at `position` to its index.
+///
+/// Every command of a batch gets pointed at its own record within the batch record, so it can be
+/// read and compacted like any other. An empty batch is a marker raising the horizon of the history
+/// to its sequence number, see [`record::marker`].
+fn load_command(namespaces: &Namespaces, namespace: u32, command: Command, position: Position) {
+    match command {
+        Command::Set((key, _, expires_at)) => {
+            load_position(
+                &namespaces.index(namespace),
+                key,
+                Position {
+                    expires_at,
+                    ..position
+                },
+            );
+        }
+        Command::Remove(key) => {
+            let store = namespaces.index(namespace);
+            store.remove(&key);
+            let removed = Revision {
+                position,
+                removed: true,
+            };
+            store.remember(&key, removed);
+        }
+        Command::Batch(commands) if commands.is_empty() => {
+            namespaces.raise_horizon(position.stamp.seq);
+        }
+        Command::Batch(commands) => {
+            let mut offset = position.offset + record::HEADER_LEN as u64;
+            for (command, seq) in commands.into_iter().zip(position.stamp.seq..) {
+                let len = record::encoded_len(&command);
+                load_command(
+                    namespaces,
+                    namespace,
+                    command,
+                    Position {
+                        offset,
+                        len,
+                        stamp: Stamp {
+                            seq,
+                            ..position.stamp
+                        },
+                        ..position
+                    },
+                );
+                offset += len;
+            }
+        }
+    }
+}
+
+/// Point `key` at `position` while loading, a record that expired in the meantime acts as a remove
+/// but is still part of the history.
+fn load_position(store: &Index, key: Vec<u8>, position: Position) {
+    if position.is_expired() {
+        store.remove(&key);
+        let expired = Revision {
+            position,
+            removed: false,
+        };
+        store.remember(&key, expired);
+    } else {
+        store.insert(key, position);
+    }
+}
+
+/// Read hint file of segment `id`, if there is one and it's still valid.
+fn load_hint(dir: &Path, id: u64, segment_len: u64) -> Option<(u64, Vec<HintEntry>)> {
+    let path = hint_path(dir, id);
+    if !path.exists() {
+        return None;
+    }
+    match hint::read(&path, segment_len) {
+        Ok(Some(hints)) => Some(hints),
+        Ok(None) => {
+            warn!("{} is stale, replaying segment {}", path.display(), id);
+            None
+        }
+        Err(error) => {
+            warn!("{}, replaying segment {}", error, id);
+            None
+        }
+    }
+}
+
+/// Flush directory entries, so that created, renamed and removed files survive a crash.
+#[cfg(unix)]
+pub(super) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(super) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buffer = &mut buffer[n..];
+                offset += n as u64;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+fn value_from_file(readers: &Readers, position: Position) -> Result<Vec<u8>> {
+    readers.with_record(position, |record| {
+        value_from_record(record, position.offset)
+    })
+}
+
+/// Decode value of the set `record` found at `offset`.
+fn value_from_record(mut record: &[u8], offset: u64) -> Result<Vec<u8>> {
+    let end = offset + record.len() as u64;
+    match record::decode(&mut record, offset, end)? {
+        Some(Decoded { command, .. }) => match command {
+            Command::Set((_, value, _)) => Ok(value),
+            Command::Remove(_) | Command::Batch(_) => panic!("wrong offset"),
+        },
+        None => Err(Error::Offset(format!("no record at offset {}", offset))),
+    }
+}
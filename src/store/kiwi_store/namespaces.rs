@@ -0,0 +1,122 @@
+//! Keyspaces sharing a single log, see [`KiwiEngine::open_tree`](crate::KiwiEngine::open_tree).
+use super::{sync_dir, Index};
+use crate::store::record::DEFAULT_NAMESPACE;
+use crate::store::Retention;
+use crate::{Error, Result};
+
+use crossbeam_skiplist::SkipMap;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// File mapping names of namespaces to the ids in their records, the default one has no name.
+const NAMESPACES_FILE: &str = "namespaces.json";
+
+/// Indexes of all namespaces of a store, shared by all its handles.
+///
+/// A name gets its id when it's first opened, the mapping is rewritten atomically before any record
+/// of the new namespace can be written.
+#[derive(Debug)]
+pub(super) struct Namespaces {
+    dir: PathBuf,
+    retention: Option<Retention>,
+    indexes: SkipMap<u32, Arc<Index>>,
+    ids: Mutex<HashMap<String, u32>>,
+    /// Sequence number the history is complete from, compaction may have dropped older versions.
+    horizon: AtomicU64,
+}
+
+impl Namespaces {
+    /// Read names of namespaces of the store in `dir`, all indexes start empty.
+    pub(super) fn open(dir: &Path, retention: Option<Retention>) -> Result<Self> {
+        Ok(Namespaces {
+            dir: dir.to_path_buf(),
+            retention,
+            indexes: SkipMap::new(),
+            ids: Mutex::new(load_names(dir)?),
+            horizon: AtomicU64::new(0),
+        })
+    }
+
+    /// Index of namespace `id`, created empty on first use.
+    pub(super) fn index(&self, id: u32) -> Arc<Index> {
+        if let Some(entry) = self.indexes.get(&id) {
+            return Arc::clone(entry.value());
+        }
+        let index = Arc::new(Index::new(self.retention));
+        Arc::clone(self.indexes.get_or_insert(id, index).value())
+    }
+
+    /// Indexes of all namespaces written to, together with their ids.
+    pub(super) fn indexes(&self) -> Vec<(u32, Arc<Index>)> {
+        self.indexes
+            .iter()
+            .map(|entry| (*entry.key(), Arc::clone(entry.value())))
+            .collect()
+    }
+
+    /// Id of the namespace called `name`, a new one is assigned and saved on first use.
+    ///
+    /// New ids are also above those found in the log, so that records of a namespace whose name
+    /// got lost never end up in another one.
+    pub(super) fn id(&self, name: &str) -> Result<u32> {
+        let mut ids = self.ids.lock().expect("error acquiring lock");
+        if let Some(&id) = ids.get(name) {
+            return Ok(id);
+        }
+        let loaded = self
+            .indexes
+            .back()
+            .map_or(DEFAULT_NAMESPACE, |entry| *entry.key());
+        let id = ids
+            .values()
+            .copied()
+            .fold(loaded, u32::max)
+            .checked_add(1)
+            .ok_or_else(|| Error::Other("too many namespaces".to_owned()))?;
+        ids.insert(name.to_owned(), id);
+        if let Err(error) = save_names(&self.dir, &ids) {
+            ids.remove(name);
+            return Err(error);
+        }
+        Ok(id)
+    }
+
+    /// Id of the namespace called `name`, `None` if it was never opened.
+    pub(super) fn find_id(&self, name: &str) -> Option<u32> {
+        let ids = self.ids.lock().expect("error acquiring lock");
+        ids.get(name).copied()
+    }
+
+    pub(super) fn horizon(&self) -> u64 {
+        self.horizon.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn raise_horizon(&self, horizon: u64) {
+        self.horizon.fetch_max(horizon, Ordering::SeqCst);
+    }
+}
+
+/// Ids of named namespaces of the store in `dir`, none if it has no names file yet.
+pub(crate) fn load_names(dir: &Path) -> Result<HashMap<String, u32>> {
+    match File::open(dir.join(NAMESPACES_FILE)) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Replace the names file of the store in `dir` with `ids`, atomically.
+pub(crate) fn save_names(dir: &Path, ids: &HashMap<String, u32>) -> Result<()> {
+    let path = dir.join(NAMESPACES_FILE);
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, ids)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(dir)?;
+    Ok(())
+}
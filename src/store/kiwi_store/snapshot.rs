@@ -0,0 +1,110 @@
+//! Point-in-time views of the store, see [`KiwiStoreSnapshot`].
+use super::{read_exact_at, value_from_record, Position};
+use crate::store::{BytesIter, KiwiSnapshot};
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::vec;
+
+/// Read-only view of a [`KiwiStore`](crate::KiwiStore) as of the moment it was taken.
+///
+/// Holds a frozen copy of the index and an open handle to every segment it points into, so it
+/// keeps reading the same values while writes go on and compaction removes segments. Space of
+/// removed segments is given back once the last snapshot using them is dropped. Keys that expire
+/// after the snapshot was taken stay visible through it.
+#[derive(Clone)]
+pub struct KiwiStoreSnapshot {
+    frozen: Arc<Frozen>,
+}
+
+struct Frozen {
+    positions: BTreeMap<Vec<u8>, Position>,
+    segments: BTreeMap<u64, Arc<File>>,
+}
+
+impl KiwiStoreSnapshot {
+    pub(super) fn new(
+        positions: BTreeMap<Vec<u8>, Position>,
+        segments: BTreeMap<u64, Arc<File>>,
+    ) -> Self {
+        KiwiStoreSnapshot {
+            frozen: Arc::new(Frozen {
+                positions,
+                segments,
+            }),
+        }
+    }
+}
+
+impl Frozen {
+    fn read(&self, position: Position) -> Result<Vec<u8>> {
+        let file = &self.segments[&position.segment];
+        let mut record = vec![0u8; position.len as usize];
+        read_exact_at(file, &mut record, position.offset)?;
+        value_from_record(&record, position.offset)
+    }
+}
+
+impl KiwiSnapshot for KiwiStoreSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.frozen.positions.get(key) {
+            Some(&position) => Ok(Some(self.frozen.read(position)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Positions in `range` are collected up front, values are read lazily.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter> {
+        let entries = self
+            .frozen
+            .positions
+            .range(range)
+            .map(|(key, &position)| (key.clone(), position))
+            .collect::<Vec<_>>();
+        Ok(Box::new(SnapshotScan {
+            frozen: Arc::clone(&self.frozen),
+            entries: entries.into_iter(),
+        }))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter> {
+        let entries = self
+            .frozen
+            .positions
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, &position)| (key.clone(), position))
+            .collect::<Vec<_>>();
+        Ok(Box::new(SnapshotScan {
+            frozen: Arc::clone(&self.frozen),
+            entries: entries.into_iter(),
+        }))
+    }
+}
+
+impl fmt::Debug for KiwiStoreSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KiwiStoreSnapshot")
+            .field("keys", &self.frozen.positions.len())
+            .field("segments", &self.frozen.segments.keys())
+            .finish()
+    }
+}
+
+struct SnapshotScan {
+    frozen: Arc<Frozen>,
+    entries: vec::IntoIter<(Vec<u8>, Position)>,
+}
+
+impl Iterator for SnapshotScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, position) = self.entries.next()?;
+        Some(self.frozen.read(position).map(|value| (key, value)))
+    }
+}
@@ -0,0 +1,136 @@
+//! Group commit of concurrent writes, see [`Writer`].
+use super::{Compactor, KiwiStoreInner};
+use crate::store::Command;
+use crate::{Error, Result};
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Most writes appended to the log as a single batch.
+const MAX_BATCH: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Queue {
+    next_id: u64,
+    /// Writes waiting to be picked up by a leader, with the namespace they go to.
+    pending: VecDeque<(u64, (u32, Command))>,
+    /// Outcomes of writes done by a leader on behalf of other writers.
+    done: HashMap<u64, Result<()>>,
+    /// Whether some writer is currently writing a batch.
+    leader_active: bool,
+}
+
+/// Serializes all writes to the log, with group commit.
+///
+/// Every writer joins a queue. The one at its front becomes the leader, appends all queued writes
+/// to the log as a single batch, flushes once and hands out outcomes to the others. Writes queued
+/// in the meantime make the next batch. An uncontended write is simply written by its own thread.
+#[derive(Debug)]
+pub(super) struct Writer {
+    inner: Arc<Mutex<KiwiStoreInner>>,
+    queue: Mutex<Queue>,
+    /// Signalled when a batch is done.
+    batch_done: Condvar,
+    compactor: Compactor,
+}
+
+impl Writer {
+    pub(super) fn new(inner: Arc<Mutex<KiwiStoreInner>>) -> Result<Self> {
+        Ok(Writer {
+            inner,
+            queue: Mutex::new(Queue::default()),
+            batch_done: Condvar::new(),
+            compactor: Compactor::new()?,
+        })
+    }
+
+    /// Queue `command` on keys of `namespace` and wait until it's written, either by this thread or
+    /// by another one.
+    pub(super) fn write(&self, namespace: u32, command: Command) -> Result<()> {
+        let mut queue = self.queue.lock().expect("error acquiring lock");
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.pending.push_back((id, (namespace, command)));
+
+        loop {
+            if let Some(outcome) = queue.done.remove(&id) {
+                return outcome;
+            }
+            if !queue.leader_active && queue.pending.front().map(|(front, _)| *front) == Some(id) {
+                break;
+            }
+            queue = self.batch_done.wait(queue).expect("error acquiring lock");
+        }
+
+        // this thread is the leader now
+        let batch_len = queue.pending.len().min(MAX_BATCH);
+        let (ids, commands): (Vec<_>, Vec<_>) = queue.pending.drain(..batch_len).unzip();
+        queue.leader_active = true;
+        drop(queue);
+
+        let outcomes = self.write_batch(commands);
+
+        let mut queue = self.queue.lock().expect("error acquiring lock");
+        queue.leader_active = false;
+        let mut own_outcome = None;
+        match outcomes {
+            Ok(outcomes) => {
+                for (write_id, outcome) in ids.into_iter().zip(outcomes) {
+                    if write_id == id {
+                        own_outcome = Some(outcome);
+                    } else {
+                        queue.done.insert(write_id, outcome);
+                    }
+                }
+            }
+            Err(error) => {
+                for write_id in ids {
+                    queue.done.insert(write_id, Err(copy_error(&error)));
+                }
+                own_outcome = queue.done.remove(&id);
+            }
+        }
+        self.batch_done.notify_all();
+
+        own_outcome.expect("leader is part of its own batch")
+    }
+
+    /// Run `f` with exclusive access to the log, for writes that depend on its current state.
+    ///
+    /// Bypasses the queue, writes queued in the meantime haven't been acknowledged yet, so they
+    /// may as well come after.
+    pub(super) fn exclusive<T>(
+        &self,
+        f: impl FnOnce(&mut KiwiStoreInner) -> Result<T>,
+    ) -> Result<T> {
+        let mut inner = self.inner.lock().expect("error acquiring lock");
+        let result = f(&mut inner)?;
+        self.compactor.maybe_compact(&self.inner, &mut inner)?;
+        Ok(result)
+    }
+
+    /// Drop all expired keys from the index, their records are garbage for the next compaction.
+    pub(super) fn sweep(&self) -> Result<()> {
+        let mut inner = self.inner.lock().expect("error acquiring lock");
+        if inner.sweep_expired() > 0 {
+            self.compactor.maybe_compact(&self.inner, &mut inner)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&self, commands: Vec<(u32, Command)>) -> Result<Vec<Result<()>>> {
+        let mut inner = self.inner.lock().expect("error acquiring lock");
+        let outcomes = inner.write_batch(commands)?;
+        self.compactor.maybe_compact(&self.inner, &mut inner)?;
+        Ok(outcomes)
+    }
+}
+
+/// Copy of `error` for every writer of a failed batch, [`Error`] itself isn't `Clone`.
+fn copy_error(error: &Error) -> Error {
+    match error {
+        Error::Io(error) => Error::Io(io::Error::new(error.kind(), error.to_string())),
+        error => Error::Other(error.to_string()),
+    }
+}
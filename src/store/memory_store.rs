@@ -0,0 +1,819 @@
+//! Engine keeping everything in memory, see [`MemoryStore`].
+//!
+//! Snapshots are saved in the log format of [`KiwiStore`](crate::KiwiStore): a directory holding
+//! the names of the namespaces and a single segment with one record per key, or per version of a
+//! key with [`Retention`]. A saved directory can be opened with `KiwiStore` too, and a `KiwiStore`
+//! directory can be loaded into a `MemoryStore`.
+use super::kiwi_store::{hint_path, list_segments, load_names, save_names, segment_path, sync_dir};
+use crate::store::counter;
+use crate::store::expiry::{self, Sweeper};
+use crate::store::record::{self, Decoded, Stamp, DEFAULT_NAMESPACE};
+use crate::store::watch::Watchers;
+use crate::store::{
+    BytesIter, Command, CompareAndSwapError, CompareAndSwapResult, KeyVersion, KiwiEngine,
+    KiwiSnapshot, Retention, Watcher, WriteBatch,
+};
+use crate::{Error, Result};
+
+use crossbeam_skiplist::{SkipMap, SkipSet};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use std::vec;
+
+/// Value of a key written with sequence number `stamp.seq`, or its removal.
+#[derive(Debug, Clone, PartialEq)]
+struct Version {
+    /// `None` for a remove.
+    value: Option<Vec<u8>>,
+    expires_at: Option<u64>,
+    stamp: Stamp,
+}
+
+impl Version {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(expiry::is_expired)
+    }
+}
+
+/// Keys of a single namespace.
+///
+/// Readers look keys up without any lock, it's modified only under the write lock, see [`Writer`].
+#[derive(Debug, Default)]
+struct Tree {
+    /// Current value of every key. Overwrites update an entry in place, replacing it would make the
+    /// key briefly missing.
+    entries: SkipMap<Vec<u8>, RwLock<Version>>,
+    /// Keys that have an expiry, ordered by it, so that expired ones are found without a full scan.
+    expiries: SkipSet<(u64, Vec<u8>)>,
+    /// Versions of every key, oldest first, tracked only with [`Retention`].
+    history: Option<SkipMap<Vec<u8>, Mutex<Vec<Version>>>>,
+}
+
+impl Tree {
+    fn new(retention: Option<Retention>) -> Self {
+        Tree {
+            history: retention.map(|_| SkipMap::new()),
+            ..Tree::default()
+        }
+    }
+
+    /// Current value of `key`, `None` if it's missing or expired.
+    fn get(&self, key: &[u8]) -> Option<Version> {
+        let entry = self.entries.get(key)?;
+        let version = entry.value().read().expect("error acquiring lock").clone();
+        Some(version).filter(|version| !version.is_expired())
+    }
+
+    /// Version of `key` for transactions, the sequence number of its last write or zero if it's
+    /// missing or expired.
+    fn version(&self, key: &[u8]) -> u64 {
+        self.get(key).map_or(0, |version| version.stamp.seq)
+    }
+
+    /// Make `version` the current one of `key` and add it to the history.
+    fn apply(&self, key: Vec<u8>, version: Version) {
+        if let Some(entry) = self.entries.get(&key) {
+            let old = entry
+                .value()
+                .read()
+                .expect("error acquiring lock")
+                .expires_at;
+            if let Some(expires_at) = old {
+                self.expiries.remove(&(expires_at, key.clone()));
+            }
+        }
+        if let Some(history) = &self.history {
+            match history.get(&key) {
+                Some(entry) => entry
+                    .value()
+                    .lock()
+                    .expect("error acquiring lock")
+                    .push(version.clone()),
+                None => {
+                    history.insert(key.clone(), Mutex::new(vec![version.clone()]));
+                }
+            }
+        }
+        if version.value.is_none() {
+            self.entries.remove(&key);
+            return;
+        }
+        if let Some(expires_at) = version.expires_at {
+            self.expiries.insert((expires_at, key.clone()));
+        }
+        match self.entries.get(&key) {
+            Some(entry) => *entry.value().write().expect("error acquiring lock") = version,
+            None => {
+                self.entries.insert(key, RwLock::new(version));
+            }
+        }
+    }
+
+    /// Remove all expired keys, their versions stay in the history.
+    fn reap_expired(&self) {
+        while let Some(entry) = self.expiries.front() {
+            let (expires_at, key) = entry.value().clone();
+            if !expiry::is_expired(expires_at) {
+                break;
+            }
+            entry.remove();
+            if let Some(entry) = self.entries.get(&key) {
+                if entry
+                    .value()
+                    .read()
+                    .expect("error acquiring lock")
+                    .expires_at
+                    == Some(expires_at)
+                {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    /// Drop versions `retention` doesn't keep, returns whether any got dropped.
+    ///
+    /// Versions are kept from the newest one back to the first one not retained, the newest one is
+    /// always kept while the key is live.
+    fn trim_history(&self, retention: &Retention) -> bool {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return false,
+        };
+        let mut trimmed = false;
+        for entry in history.iter() {
+            let live = self.get(entry.key()).is_some();
+            let mut versions = entry.value().lock().expect("error acquiring lock");
+            let kept = versions
+                .iter()
+                .rev()
+                .enumerate()
+                .take_while(|&(newer, version)| {
+                    (newer == 0 && live) || retention.keeps(newer, version.stamp.timestamp)
+                })
+                .count();
+            let dropped = versions.len() - kept;
+            if dropped > 0 {
+                versions.drain(..dropped);
+                trimmed = true;
+            }
+            if versions.is_empty() {
+                entry.remove();
+            }
+        }
+        trimmed
+    }
+
+    /// Versions to save, oldest first for every key: the whole history if it's tracked, live values
+    /// otherwise.
+    fn saved(&self) -> Vec<(Vec<u8>, Version)> {
+        match &self.history {
+            Some(history) => history
+                .iter()
+                .flat_map(|entry| {
+                    let versions = entry.value().lock().expect("error acquiring lock").clone();
+                    let key = entry.key().clone();
+                    versions
+                        .into_iter()
+                        .map(move |version| (key.clone(), version))
+                })
+                .collect(),
+            None => self
+                .entries
+                .iter()
+                .filter_map(|entry| Some((entry.key().clone(), self.get(entry.key())?)))
+                .collect(),
+        }
+    }
+}
+
+/// State guarded by the write lock.
+#[derive(Debug)]
+struct Writer {
+    /// Sequence number of the next write.
+    next_seq: u64,
+    watchers: Watchers,
+}
+
+/// State shared by handles of all namespaces of a store.
+#[derive(Debug)]
+struct Shared {
+    retention: Option<Retention>,
+    trees: SkipMap<u32, Arc<Tree>>,
+    /// Ids of namespaces opened with [`KiwiEngine::open_tree`], by name.
+    names: Mutex<HashMap<String, u32>>,
+    writer: Mutex<Writer>,
+    /// Sequence number the history is complete from, versions written before may have been dropped.
+    horizon: AtomicU64,
+}
+
+impl Shared {
+    /// Keys of namespace `id`, created empty on first use.
+    fn tree(&self, id: u32) -> Arc<Tree> {
+        if let Some(entry) = self.trees.get(&id) {
+            return Arc::clone(entry.value());
+        }
+        let tree = Arc::new(Tree::new(self.retention));
+        Arc::clone(self.trees.get_or_insert(id, tree).value())
+    }
+
+    /// Id of the namespace called `name`, `None` if it was never opened.
+    fn find_id(&self, name: &str) -> Option<u32> {
+        let names = self.names.lock().expect("error acquiring lock");
+        names.get(name).copied()
+    }
+
+    /// Id of the namespace called `name`, a new one is assigned on first use.
+    fn id(&self, name: &str) -> Result<u32> {
+        let mut names = self.names.lock().expect("error acquiring lock");
+        if let Some(&id) = names.get(name) {
+            return Ok(id);
+        }
+        // ids loaded from a snapshot may have lost their names
+        let loaded = self
+            .trees
+            .back()
+            .map_or(DEFAULT_NAMESPACE, |entry| *entry.key());
+        let id = names
+            .values()
+            .copied()
+            .fold(loaded, u32::max)
+            .checked_add(1)
+            .ok_or_else(|| Error::Other("too many namespaces".to_owned()))?;
+        names.insert(name.to_owned(), id);
+        Ok(id)
+    }
+
+    /// Remove expired keys of all namespaces and versions [`Retention`] doesn't keep anymore.
+    fn sweep(&self) {
+        let writer = self.writer.lock().expect("error acquiring lock");
+        let mut trimmed = false;
+        for entry in self.trees.iter() {
+            entry.value().reap_expired();
+            if let Some(retention) = &self.retention {
+                trimmed |= entry.value().trim_history(retention);
+            }
+        }
+        if trimmed {
+            // versions written up to now may be gone, see `KiwiEngine::get_at_bytes`
+            self.horizon.fetch_max(writer.next_seq, Ordering::SeqCst);
+        }
+    }
+
+    /// Replay segment at `path`, returns the sequence number following the last one in it.
+    ///
+    /// An incomplete record at the end of the last segment is ignored, like a crash cut it short.
+    fn load_segment(&self, path: &Path, is_last: bool) -> Result<u64> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = record::MAGIC.len() as u64;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut next_seq = 0;
+
+        loop {
+            let Decoded {
+                command,
+                namespace,
+                stamp,
+                len,
+            } = match record::decode(&mut reader, offset, file_len) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => break,
+                Err(error) if is_last && record::is_torn_tail(&error, path, offset)? => {
+                    warn!(
+                        "{}: ignoring {} bytes of incomplete record at offset {}",
+                        path.display(),
+                        file_len - offset,
+                        offset
+                    );
+                    break;
+                }
+                Err(error) => return Err(error),
+            };
+            next_seq = next_seq.max(stamp.seq + record::seq_count(&command));
+            self.load_command(namespace, command, stamp);
+            offset += len;
+        }
+        Ok(next_seq)
+    }
+
+    /// Apply `command` on keys of `namespace` written with `stamp`. An empty batch is a marker
+    /// raising the horizon of the history, see [`record::marker`].
+    fn load_command(&self, namespace: u32, command: Command, stamp: Stamp) {
+        let (key, version) = match command {
+            Command::Set((key, value, expires_at)) => (
+                key,
+                Version {
+                    value: Some(value),
+                    expires_at,
+                    stamp,
+                },
+            ),
+            Command::Remove(key) => (
+                key,
+                Version {
+                    value: None,
+                    expires_at: None,
+                    stamp,
+                },
+            ),
+            Command::Batch(commands) if commands.is_empty() => {
+                self.horizon.fetch_max(stamp.seq, Ordering::SeqCst);
+                return;
+            }
+            Command::Batch(commands) => {
+                for (command, seq) in commands.into_iter().zip(stamp.seq..) {
+                    self.load_command(namespace, command, Stamp { seq, ..stamp });
+                }
+                return;
+            }
+        };
+        self.tree(namespace).apply(key, version);
+    }
+
+    /// Write all namespaces to a new segment at `path`, starting with a marker of `horizon`.
+    fn write_segment(&self, path: &Path, horizon: u64) -> Result<()> {
+        let mut log = BufWriter::new(record::create_log(path)?);
+        log.write_all(&record::marker(horizon))?;
+        for entry in self.trees.iter() {
+            for (key, version) in entry.value().saved() {
+                let command = match version.value {
+                    Some(value) => Command::Set((key, value, version.expires_at)),
+                    None => Command::Remove(key),
+                };
+                log.write_all(&record::encode(&command, *entry.key(), version.stamp))?;
+            }
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Engine keeping all keys in concurrent ordered maps in memory, nothing is written to disk.
+///
+/// Everything is lost once the last handle is dropped, unless saved with [`MemoryStore::save`] and
+/// loaded again with [`MemoryStore::load`]. Reads don't take any lock, writes are serialized.
+/// # Example
+/// ```
+/// # use std::error::Error;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// use kiwi_store::{KiwiEngine, MemoryStore};
+/// let store = MemoryStore::new();
+///
+/// store.set("key1".to_owned(), "value1".to_owned())?;
+/// assert_eq!(Some("value1".to_owned()), store.get("key1".to_owned())?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    /// Id of the namespace of this handle, see [`KiwiEngine::open_tree`].
+    namespace: u32,
+    /// Keys of `namespace`.
+    tree: Arc<Tree>,
+    shared: Arc<Shared>,
+    /// Removes expired keys in the background, stopped once the last handle is dropped.
+    _sweeper: Arc<Sweeper>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    /// Empty store that keeps current values only.
+    pub fn new() -> Self {
+        Self::start(None)
+    }
+
+    /// Empty store that records every write and keeps past versions according to `retention`,
+    /// see [`KiwiEngine::history`]. Versions beyond `retention` are dropped in the background.
+    pub fn with_retention(retention: Retention) -> Self {
+        Self::start(Some(retention))
+    }
+
+    /// Store with keys of the log in `dir`, keeping current values only. A missing directory makes
+    /// an empty store, see [`MemoryStore::save`].
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::load_from(dir.as_ref(), None)
+    }
+
+    /// Like [`MemoryStore::load`], but keeps versions of keys found in the log according to
+    /// `retention`, see [`MemoryStore::with_retention`].
+    pub fn load_with_retention(dir: impl AsRef<Path>, retention: Retention) -> Result<Self> {
+        Self::load_from(dir.as_ref(), Some(retention))
+    }
+
+    fn load_from(dir: &Path, retention: Option<Retention>) -> Result<Self> {
+        let store = Self::start(retention);
+        if !dir.exists() {
+            return Ok(store);
+        }
+        *store.shared.names.lock().expect("error acquiring lock") = load_names(dir)?;
+        let ids = list_segments(dir)?;
+        let mut next_seq = 1;
+        for (index, &id) in ids.iter().enumerate() {
+            let is_last = index + 1 == ids.len();
+            next_seq = next_seq.max(store.shared.load_segment(&segment_path(dir, id), is_last)?);
+        }
+        store
+            .shared
+            .writer
+            .lock()
+            .expect("error acquiring lock")
+            .next_seq = next_seq;
+        Ok(store)
+    }
+
+    fn start(retention: Option<Retention>) -> Self {
+        let shared = Arc::new(Shared {
+            retention,
+            trees: SkipMap::new(),
+            names: Mutex::new(HashMap::new()),
+            writer: Mutex::new(Writer {
+                next_seq: 1,
+                watchers: Watchers::default(),
+            }),
+            horizon: AtomicU64::new(0),
+        });
+        let sweep_shared = Arc::clone(&shared);
+        MemoryStore {
+            namespace: DEFAULT_NAMESPACE,
+            tree: shared.tree(DEFAULT_NAMESPACE),
+            shared,
+            _sweeper: Arc::new(Sweeper::start(expiry::SWEEP_INTERVAL, move || {
+                sweep_shared.sweep();
+                Ok(true)
+            })),
+        }
+    }
+
+    /// Write keys of all namespaces to `dir` in the log format, replacing any log already there.
+    /// The directory is created if it's missing.
+    ///
+    /// Writes are blocked until the log is on disk, so that it holds a consistent state of the
+    /// store. Without retention, neither removed keys nor past versions are saved.
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let path = segment_path(dir, 1);
+        let tmp_path = path.with_extension("log.tmp");
+
+        let writer = self.shared.writer.lock().expect("error acquiring lock");
+        let horizon = match self.shared.retention {
+            Some(_) => self.shared.horizon.load(Ordering::SeqCst),
+            None => writer.next_seq,
+        };
+        let names = self
+            .shared
+            .names
+            .lock()
+            .expect("error acquiring lock")
+            .clone();
+        let written = self.shared.write_segment(&tmp_path, horizon);
+        drop(writer);
+        if let Err(error) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(error);
+        }
+
+        save_names(dir, &names)?;
+        // a hint left from an earlier segment 1 would describe other records
+        if let Err(error) = fs::remove_file(hint_path(dir, 1)) {
+            if error.kind() != io::ErrorKind::NotFound {
+                return Err(error.into());
+            }
+        }
+        fs::rename(&tmp_path, &path)?;
+        for id in list_segments(dir)? {
+            if id != 1 {
+                fs::remove_file(segment_path(dir, id))?;
+                let _ = fs::remove_file(hint_path(dir, id));
+            }
+        }
+        sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Apply `commands` under the write lock held as `writer`, all with the same timestamp.
+    ///
+    /// Removing a missing key is skipped, it doesn't take a sequence number.
+    fn apply(&self, writer: &mut Writer, commands: Vec<Command>) {
+        let stamp = Stamp::now(writer.next_seq);
+        for command in commands {
+            let (key, version) = match &command {
+                Command::Set((key, value, expires_at)) => (
+                    key.clone(),
+                    Version {
+                        value: Some(value.clone()),
+                        expires_at: *expires_at,
+                        stamp: Stamp {
+                            seq: writer.next_seq,
+                            ..stamp
+                        },
+                    },
+                ),
+                Command::Remove(key) if self.tree.get(key).is_some() => (
+                    key.clone(),
+                    Version {
+                        value: None,
+                        expires_at: None,
+                        stamp: Stamp {
+                            seq: writer.next_seq,
+                            ..stamp
+                        },
+                    },
+                ),
+                Command::Remove(_) => continue,
+                Command::Batch(_) => unreachable!("batches are never nested"),
+            };
+            let seq = writer.next_seq;
+            writer.next_seq += 1;
+            if !writer.watchers.is_empty() {
+                writer.watchers.publish(self.namespace, &command, seq);
+            }
+            self.tree.apply(key, version);
+        }
+    }
+
+    fn write(&self, commands: Vec<Command>) {
+        let mut writer = self.shared.writer.lock().expect("error acquiring lock");
+        self.apply(&mut writer, commands);
+    }
+}
+
+impl KiwiEngine for MemoryStore {
+    /// Sequence number of the last write to the key.
+    type Version = u64;
+    type Snapshot = MemoryStoreSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(vec![Command::Set((key, value, None))]);
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        self.write(vec![Command::Set((key, value, Some(expires_at)))]);
+        Ok(())
+    }
+
+    /// Get a value. Doesn't take any lock.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree.get(key).and_then(|version| version.value))
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        Ok(self
+            .tree
+            .get(key)
+            .and_then(|version| version.expires_at)
+            .map(expiry::remaining))
+    }
+
+    /// Remove a value, fails with [`Error::NoKey`] if it's missing.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut writer = self.shared.writer.lock().expect("error acquiring lock");
+        if self.tree.get(&key).is_none() {
+            return Err(Error::NoKey(String::from("Key not found")));
+        }
+        self.apply(&mut writer, vec![Command::Remove(key)]);
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        let mut writer = self.shared.writer.lock().expect("error acquiring lock");
+        let current = self.tree.get(&key).and_then(|version| version.value);
+        if current != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+        let command = match new {
+            Some(value) => Command::Set((key, value, None)),
+            None => Command::Remove(key),
+        };
+        self.apply(&mut writer, vec![command]);
+        Ok(Ok(()))
+    }
+
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let mut writer = self.shared.writer.lock().expect("error acquiring lock");
+        let (current, expires_at) = match self.tree.get(&key) {
+            Some(version) => (version.value, version.expires_at),
+            None => (None, None),
+        };
+        let (count, value) = counter::increment(current.as_deref(), delta)?;
+        self.apply(&mut writer, vec![Command::Set((key, value, expires_at))]);
+        Ok(count)
+    }
+
+    /// Apply a batch under the write lock, so snapshots and transactions see it as a whole.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(batch.into_commands());
+        Ok(())
+    }
+
+    /// Copies all live pairs under the write lock, blocking writes until it's done.
+    fn snapshot(&self) -> Result<MemoryStoreSnapshot> {
+        let _writer = self.shared.writer.lock().expect("error acquiring lock");
+        let pairs = self
+            .tree
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                let version = self.tree.get(entry.key())?;
+                Some((entry.key().clone(), version.value?))
+            })
+            .collect();
+        Ok(MemoryStoreSnapshot {
+            pairs: Arc::new(pairs),
+        })
+    }
+
+    /// Read a value and its version without taking any lock.
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, u64)> {
+        match self.tree.get(key) {
+            Some(version) => Ok((version.value, version.stamp.seq)),
+            None => Ok((None, 0)),
+        }
+    }
+
+    /// Validate and apply a transaction under the write lock.
+    fn commit_transaction(&self, reads: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()> {
+        let mut writer = self.shared.writer.lock().expect("error acquiring lock");
+        for (key, version) in reads {
+            if self.tree.version(&key) != version {
+                return Err(Error::Conflict(key));
+            }
+        }
+        self.apply(&mut writer, batch.into_commands());
+        Ok(())
+    }
+
+    /// Versions kept in memory. Without retention that's the current value only.
+    fn history_bytes(&self, key: &[u8]) -> Result<Vec<KeyVersion<Vec<u8>>>> {
+        let versions = match &self.tree.history {
+            Some(history) => match history.get(key) {
+                Some(entry) => entry.value().lock().expect("error acquiring lock").clone(),
+                None => Vec::new(),
+            },
+            None => self.tree.get(key).into_iter().collect(),
+        };
+        Ok(versions
+            .into_iter()
+            .map(|version| {
+                let Stamp { seq, timestamp } = version.stamp;
+                KeyVersion::new(seq, timestamp, version.value)
+            })
+            .collect())
+    }
+
+    /// Read a past value without taking any lock. Without retention only the current value can be
+    /// told.
+    fn get_at_bytes(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let history = match &self.tree.history {
+            Some(history) => history,
+            None => {
+                return match self.tree.get(key) {
+                    Some(version) if version.stamp.seq <= seq => Ok(version.value),
+                    _ => Err(Error::HistoryTruncated(seq)),
+                }
+            }
+        };
+        let version = history.get(key).and_then(|entry| {
+            let versions = entry.value().lock().expect("error acquiring lock");
+            versions
+                .iter()
+                .rev()
+                .find(|version| version.stamp.seq <= seq)
+                .cloned()
+        });
+        match version {
+            Some(version) => Ok(version.value),
+            None if seq >= self.shared.horizon.load(Ordering::SeqCst) => Ok(None),
+            None => Err(Error::HistoryTruncated(seq)),
+        }
+    }
+
+    /// Iterate over key-value pairs in `range`. Keys are collected up front, values are read lazily.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter> {
+        let keys: Vec<_> = self
+            .tree
+            .entries
+            .range(range)
+            .map(|entry| entry.key().clone())
+            .collect();
+        Ok(Box::new(Scan {
+            tree: Arc::clone(&self.tree),
+            keys: keys.into_iter(),
+        }))
+    }
+
+    /// Iterate over key-value pairs with keys starting with `prefix`.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter> {
+        let keys: Vec<_> = self
+            .tree
+            .entries
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| entry.key().clone())
+            .collect();
+        Ok(Box::new(Scan {
+            tree: Arc::clone(&self.tree),
+            keys: keys.into_iter(),
+        }))
+    }
+
+    /// Register a watcher under the write lock, so it sees exactly the writes applied after it.
+    /// Expired keys aren't reported.
+    fn watch_bytes(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        let mut writer = self.shared.writer.lock().expect("error acquiring lock");
+        Ok(writer.watchers.watch(self.namespace, prefix))
+    }
+
+    /// Handle sharing the write lock and the background sweeper with this one. The name gets its id
+    /// on first use, it's saved along with the keys.
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        let namespace = self.shared.id(name)?;
+        Ok(MemoryStore {
+            namespace,
+            tree: self.shared.tree(namespace),
+            ..self.clone()
+        })
+    }
+
+    fn find_tree(&self, name: &str) -> Result<Option<Self>> {
+        Ok(self.shared.find_id(name).map(|namespace| MemoryStore {
+            namespace,
+            tree: self.shared.tree(namespace),
+            ..self.clone()
+        }))
+    }
+}
+
+/// Iterator returned by [`MemoryStore`] scans, skips keys removed after the scan started.
+struct Scan {
+    tree: Arc<Tree>,
+    keys: vec::IntoIter<Vec<u8>>,
+}
+
+impl Iterator for Scan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in &mut self.keys {
+            if let Some(value) = self.tree.get(&key).and_then(|version| version.value) {
+                return Some(Ok((key, value)));
+            }
+        }
+        None
+    }
+}
+
+/// Read-only view of a [`MemoryStore`] as of the moment it was taken, all live pairs are copied.
+#[derive(Debug, Clone)]
+pub struct MemoryStoreSnapshot {
+    pairs: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStoreSnapshot {
+    fn collect<'a>(pairs: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>) -> BytesIter {
+        let pairs = pairs
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect::<Vec<_>>();
+        Box::new(pairs.into_iter())
+    }
+}
+
+impl KiwiSnapshot for MemoryStoreSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(key).cloned())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter> {
+        Ok(Self::collect(self.pairs.range(range)))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter> {
+        Ok(Self::collect(
+            self.pairs
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix)),
+        ))
+    }
+}
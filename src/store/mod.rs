@@ -1,21 +1,279 @@
+mod counter;
+mod expiry;
+mod hint;
+mod history;
 mod kiwi_store;
+mod memory_store;
+mod record;
 mod sled_store;
+mod transaction;
+mod typed_store;
+mod watch;
+mod write_batch;
 
 use crate::Result;
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
 
-pub use self::kiwi_store::KiwiStore;
-pub use self::sled_store::SledStore;
+pub use self::history::{KeyVersion, Retention};
+pub use self::kiwi_store::{
+    Changes, Durability, KiwiStore, KiwiStoreOptions, KiwiStoreSnapshot, ReadMode,
+};
+pub use self::memory_store::{MemoryStore, MemoryStoreSnapshot};
+pub use self::sled_store::{SledStore, SledStoreSnapshot};
+pub use self::transaction::Transaction;
+pub use self::typed_store::TypedStore;
+pub use self::watch::{ChangeEvent, Watcher};
+pub use self::write_batch::WriteBatch;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Clone, Debug)]
 enum Command {
-    Set((String, String)),
-    Remove(String),
+    /// Key, value and optional expiry, see [`expiry`].
+    Set((Vec<u8>, Vec<u8>, Option<u64>)),
+    Remove(Vec<u8>),
+    /// Commands applied all-or-nothing, never nested.
+    Batch(Vec<Command>),
+}
+
+/// Iterator over key-value pairs in ascending order of keys, returned by scans
+pub type KvIter = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Iterator over raw key-value pairs in ascending byte order of keys, returned by byte scans
+pub type BytesIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Outcome of a compare-and-swap, see [`KiwiEngine::compare_and_swap`].
+pub type CompareAndSwapResult<V> = std::result::Result<(), CompareAndSwapError<V>>;
+
+/// Returned by a compare-and-swap whose expected value didn't match, holds the current value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareAndSwapError<V> {
+    pub current: Option<V>,
 }
 
 /// Provides a generic set of actions extracted from KvStore
+///
+/// Keys and values are arbitrary bytes. The `String` methods are a convenience layer on top of the
+/// byte ones, reading data that isn't valid UTF-8 through them fails with [`crate::Error::Utf8Error`].
 pub trait KiwiEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// Version of a key remembered by a [`Transaction`] that read it.
+    type Version: Clone + PartialEq + Send + fmt::Debug;
+    /// Read-only view returned by [`KiwiEngine::snapshot`].
+    type Snapshot: KiwiSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Set a value that expires after `ttl`, expired keys are invisible to all reads.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Time left until `key` expires, `None` if it doesn't exist or never expires.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Atomically replace value of `key` with `new` if it's currently `expected`.
+    ///
+    /// `None` stands for a missing key on both sides, so `expected: None` only creates a key and
+    /// `new: None` removes it. On mismatch nothing is written and the current value is returned.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>>;
+    /// Atomically add `delta` to the counter at `key` and return the new count.
+    ///
+    /// Counters are stored as decimal text, a missing key counts as zero and the expiry of an
+    /// existing key is kept. Fails with [`Error::InvalidCounter`](crate::Error::InvalidCounter) if
+    /// the current value isn't a 64-bit integer or the result would overflow.
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+    /// Apply all sets and removes of `batch` as a single write.
+    ///
+    /// After a crash either all of them are in the store or none of them. Other writes,
+    /// transactions and snapshots see either all of them or none of them, and a transaction that
+    /// read part of a batch fails to commit. Reads that don't take a lock, like `get` and scans of
+    /// [`KiwiStore`](crate::KiwiStore) and [`MemoryStore`](crate::MemoryStore), may see part of a
+    /// batch while it's being applied, read through a [`KiwiEngine::snapshot`] to see it whole.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Read-only view of the store as it is now, unaffected by later writes and compaction.
+    ///
+    /// Resources pinned by the snapshot are released once it's dropped.
+    ///
+    /// None of the engines can share their current state with a snapshot, so taking one copies
+    /// what is needed to read every live key of the namespace: the index of
+    /// [`KiwiStore`](crate::KiwiStore), all pairs of [`SledStore`](crate::SledStore) and
+    /// [`MemoryStore`](crate::MemoryStore). That takes time and memory proportional to the number
+    /// of keys, and writes are blocked until it's done. Meant for occasional consistent reads, not
+    /// for every read.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Read the live value of `key` together with its version, used by [`Transaction`].
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Self::Version)>;
+    /// Apply `batch` atomically if every key in `reads` still has the version read, used by
+    /// [`Transaction::commit`]. Fails with [`Error::Conflict`](crate::Error::Conflict) otherwise.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Self::Version)>,
+        batch: WriteBatch,
+    ) -> Result<()>;
+    /// Versions of `key` the engine still knows about, oldest first, see [`Retention`].
+    ///
+    /// Expiry isn't taken into account, an expired key keeps its last value in the history. Without
+    /// retention only the current value is known, if even that.
+    fn history_bytes(&self, key: &[u8]) -> Result<Vec<KeyVersion<Vec<u8>>>>;
+    /// Value of `key` right after the write with sequence number `seq`, `None` if it didn't exist.
+    ///
+    /// Fails with [`Error::HistoryTruncated`](crate::Error::HistoryTruncated) if the versions needed
+    /// to tell were already dropped, see [`Retention`].
+    fn get_at_bytes(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>>;
+    /// Iterate over all key-value pairs with keys in `range`, ordered by key.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter>;
+    /// Iterate over all key-value pairs with keys starting with `prefix`, ordered by key.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter>;
+    /// Subscribe to sets and removes of keys starting with `prefix` made from now on.
+    fn watch_bytes(&self, prefix: Vec<u8>) -> Result<Watcher>;
+    /// Handle to the namespace called `name`, a keyspace of its own within the same store, created
+    /// on first use.
+    ///
+    /// The store as opened is the default, unnamed namespace. Namespaces aren't nested, opening a
+    /// name from any handle yields the same namespace. Writes, transactions, snapshots, history and
+    /// watchers all stay within the namespace of the handle.
+    fn open_tree(&self, name: &str) -> Result<Self>;
+    /// Handle to the namespace called `name` if it was already opened, `None` otherwise.
+    ///
+    /// Unlike [`KiwiEngine::open_tree`] it never creates the namespace, for readers that would
+    /// rather treat a missing namespace as empty than leave one behind.
+    fn find_tree(&self, name: &str) -> Result<Option<Self>>;
+
+    /// Start a transaction with optimistic concurrency control, see [`Transaction`].
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(into_string).transpose()
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_bytes())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// String version of [`KiwiEngine::compare_and_swap_bytes`].
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<CompareAndSwapResult<String>> {
+        let result = self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(CompareAndSwapError { current }) => Ok(Err(CompareAndSwapError {
+                current: current.map(into_string).transpose()?,
+            })),
+        }
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr_by_bytes(key.into_bytes(), delta)
+    }
+
+    fn history(&self, key: String) -> Result<Vec<KeyVersion<String>>> {
+        self.history_bytes(key.as_bytes())?
+            .into_iter()
+            .map(|version| {
+                Ok(KeyVersion {
+                    seq: version.seq,
+                    timestamp: version.timestamp,
+                    value: version.value.map(into_string).transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    fn get_at(&self, key: String, seq: u64) -> Result<Option<String>> {
+        self.get_at_bytes(key.as_bytes(), seq)?
+            .map(into_string)
+            .transpose()
+    }
+
+    /// Iterate over all key-value pairs with keys in `range`, ordered by key.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvIter> {
+        let range = (
+            bound_to_bytes(range.start_bound()),
+            bound_to_bytes(range.end_bound()),
+        );
+        Ok(Box::new(self.scan_bytes(range)?.map(into_string_pair)))
+    }
+
+    /// Iterate over all key-value pairs with keys starting with `prefix`, ordered by key.
+    fn scan_prefix(&self, prefix: String) -> Result<KvIter> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())?
+                .map(into_string_pair),
+        ))
+    }
+
+    /// Subscribe to changes of keys starting with `prefix`, events carry raw bytes.
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        self.watch_bytes(prefix.into_bytes())
+    }
+}
+
+/// Consistent read-only view of an engine, taken by [`KiwiEngine::snapshot`].
+pub trait KiwiSnapshot: Clone + Send + 'static {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Iterate over all key-value pairs with keys in `range`, ordered by key.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter>;
+    /// Iterate over all key-value pairs with keys starting with `prefix`, ordered by key.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter>;
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(into_string).transpose()
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvIter> {
+        let range = (
+            bound_to_bytes(range.start_bound()),
+            bound_to_bytes(range.end_bound()),
+        );
+        Ok(Box::new(self.scan_bytes(range)?.map(into_string_pair)))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> Result<KvIter> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())?
+                .map(into_string_pair),
+        ))
+    }
+}
+
+/// UTF-8 sorts the same way as its bytes, so string ranges map directly onto byte ranges.
+fn bound_to_bytes(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_bytes().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_bytes().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|error| error.utf8_error().into())
+}
+
+fn into_string_pair(pair: Result<(Vec<u8>, Vec<u8>)>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((into_string(key)?, into_string(value)?))
 }
@@ -0,0 +1,352 @@
+//! Binary on-disk format of the log.
+//!
+//! A log file starts with [`MAGIC`], followed by records laid out as:
+//!
+//! ```text
+//! | crc: u32 | key_len: u32 | value_len: u32 | op: u8 | seq: u64 | timestamp: u64 | namespace: u32 | key bytes | value bytes |
+//! ```
+//!
+//! All integers are little-endian. `crc` is a CRC32 of everything in the record that follows it.
+//! `seq` is the sequence number of the write, `timestamp` the time it was written in milliseconds
+//! since the Unix epoch, see [`Stamp`]. `namespace` is the id of the keyspace the key belongs to,
+//! zero for the default one. `Remove` records carry an empty value. Values of expiring
+//! `Set` records start with the expiry as a `u64` in milliseconds since the Unix epoch. `Batch`
+//! records carry an empty key and their value is a sequence of complete records, so a torn or
+//! corrupted batch is dropped as a whole. Records of a batch get consecutive sequence numbers,
+//! starting with the one of the batch.
+use crate::store::{expiry, Command};
+use crate::{Error, Result};
+
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Bytes every log file starts with, last byte is the format version.
+pub(crate) const MAGIC: &[u8; 5] = b"KIWI\x01";
+
+/// Size of the fixed part of every record.
+pub(crate) const HEADER_LEN: usize = 4 + 4 + 4 + 1 + 8 + 8 + 4;
+
+/// Id of the namespace keys belong to unless they were written to a named one.
+pub(crate) const DEFAULT_NAMESPACE: u32 = 0;
+
+const OP_SET: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const OP_SET_EXPIRING: u8 = 2;
+const OP_BATCH: u8 = 3;
+
+/// Command as written to the legacy JSON-lines log, which only supported UTF-8 keys and values.
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set((String, String)),
+    Remove(String),
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(command: LegacyCommand) -> Self {
+        match command {
+            LegacyCommand::Set((key, value)) => {
+                Command::Set((key.into_bytes(), value.into_bytes(), None))
+            }
+            LegacyCommand::Remove(key) => Command::Remove(key.into_bytes()),
+        }
+    }
+}
+
+/// Sequence number and write time of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct Stamp {
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch, zero for records migrated from the legacy log.
+    pub timestamp: u64,
+}
+
+impl Stamp {
+    /// Stamp of a write with sequence number `seq` happening now.
+    pub(crate) fn now(seq: u64) -> Self {
+        Stamp {
+            seq,
+            timestamp: expiry::now(),
+        }
+    }
+}
+
+/// Empty batch record marking that history in the log is complete from sequence number `horizon`,
+/// versions of keys written before may have been dropped.
+pub(crate) fn marker(horizon: u64) -> Vec<u8> {
+    encode(
+        &Command::Batch(Vec::new()),
+        DEFAULT_NAMESPACE,
+        Stamp::now(horizon),
+    )
+}
+
+/// Number of sequence numbers taken by `command`, one per set or remove.
+pub(crate) fn seq_count(command: &Command) -> u64 {
+    match command {
+        Command::Batch(commands) => commands.len() as u64,
+        _ => 1,
+    }
+}
+
+/// Serialize command on keys of `namespace` into a single record.
+pub(crate) fn encode(command: &Command, namespace: u32, stamp: Stamp) -> Vec<u8> {
+    let batch;
+    let (op, key, value, expires_at) = match command {
+        Command::Set((key, value, None)) => (OP_SET, &key[..], &value[..], None),
+        Command::Set((key, value, Some(expires_at))) => {
+            (OP_SET_EXPIRING, &key[..], &value[..], Some(expires_at))
+        }
+        Command::Remove(key) => (OP_REMOVE, &key[..], &[][..], None),
+        Command::Batch(commands) => {
+            batch = commands
+                .iter()
+                .zip(stamp.seq..)
+                .flat_map(|(command, seq)| encode(command, namespace, Stamp { seq, ..stamp }))
+                .collect::<Vec<u8>>();
+            (OP_BATCH, &[][..], &batch[..], None)
+        }
+    };
+    let expiry_len = if expires_at.is_some() { 8 } else { 0 };
+
+    let mut buffer = Vec::with_capacity(HEADER_LEN + key.len() + expiry_len + value.len());
+    buffer.extend_from_slice(&[0u8; 4]);
+    buffer.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&((expiry_len + value.len()) as u32).to_le_bytes());
+    buffer.push(op);
+    buffer.extend_from_slice(&stamp.seq.to_le_bytes());
+    buffer.extend_from_slice(&stamp.timestamp.to_le_bytes());
+    buffer.extend_from_slice(&namespace.to_le_bytes());
+    buffer.extend_from_slice(key);
+    if let Some(expires_at) = expires_at {
+        buffer.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    buffer.extend_from_slice(value);
+
+    let crc = crc32fast::hash(&buffer[4..]);
+    buffer[..4].copy_from_slice(&crc.to_le_bytes());
+    buffer
+}
+
+/// Record read from the log by [`decode`].
+#[derive(Debug)]
+pub(crate) struct Decoded {
+    pub command: Command,
+    pub namespace: u32,
+    pub stamp: Stamp,
+    /// Length of the whole record in bytes.
+    pub len: u64,
+}
+
+/// Read next record from `reader`.
+///
+/// `offset` is the position of the record in the file and `end` the position the file ends at.
+/// Returns `Ok(None)` on a clean end of stream, [`Error::Corruption`] if the checksum doesn't match
+/// or the record claims to extend past `end` and an [`io::ErrorKind::UnexpectedEof`] error if the
+/// stream ends in the middle of the header.
+pub(crate) fn decode<R: Read>(reader: &mut R, offset: u64, end: u64) -> Result<Option<Decoded>> {
+    let mut header = [0u8; HEADER_LEN];
+    if !read_exact_or_eof(reader, &mut header)? {
+        return Ok(None);
+    }
+
+    let crc = u32_at(&header, 0);
+    let key_len = u32_at(&header, 4) as usize;
+    let value_len = u32_at(&header, 8) as usize;
+    let op = header[12];
+    let stamp = Stamp {
+        seq: u64_at(&header, 13),
+        timestamp: u64_at(&header, 21),
+    };
+    let namespace = u32_at(&header, 29);
+
+    // don't trust lengths of a corrupted header with an allocation
+    if offset + (HEADER_LEN + key_len + value_len) as u64 > end {
+        return Err(Error::Corruption(offset));
+    }
+    let mut body = vec![0u8; key_len + value_len];
+    reader.read_exact(&mut body)?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&body);
+    if hasher.finalize() != crc {
+        return Err(Error::Corruption(offset));
+    }
+
+    let mut value = body.split_off(key_len);
+    let key = body;
+    let command = match op {
+        OP_SET => Command::Set((key, value, None)),
+        OP_SET_EXPIRING if value.len() >= 8 => {
+            let expires_at = u64_at(&value, 0);
+            value.drain(..8);
+            Command::Set((key, value, Some(expires_at)))
+        }
+        OP_REMOVE => Command::Remove(key),
+        OP_BATCH => {
+            let start = offset + (HEADER_LEN + key_len) as u64;
+            Command::Batch(decode_batch(&value, start)?)
+        }
+        _ => return Err(Error::Corruption(offset)),
+    };
+
+    Ok(Some(Decoded {
+        command,
+        namespace,
+        stamp,
+        len: (HEADER_LEN + key_len + value_len) as u64,
+    }))
+}
+
+/// Length of the record `command` encodes to.
+pub(crate) fn encoded_len(command: &Command) -> u64 {
+    let len = match command {
+        Command::Set((key, value, None)) => key.len() + value.len(),
+        Command::Set((key, value, Some(_))) => key.len() + 8 + value.len(),
+        Command::Remove(key) => key.len(),
+        Command::Batch(commands) => {
+            return HEADER_LEN as u64 + commands.iter().map(encoded_len).sum::<u64>()
+        }
+    };
+    (HEADER_LEN + len) as u64
+}
+
+/// Decode records packed in the value of a batch record found at `offset`.
+///
+/// The batch already passed its checksum, so anything that doesn't decode is corruption.
+fn decode_batch(mut records: &[u8], mut offset: u64) -> Result<Vec<Command>> {
+    let end = offset + records.len() as u64;
+    let mut commands = Vec::new();
+    loop {
+        match decode(&mut records, offset, end) {
+            Ok(Some(Decoded {
+                command: Command::Batch(_),
+                ..
+            }))
+            | Err(_) => return Err(Error::Corruption(offset)),
+            Ok(Some(Decoded { command, len, .. })) => {
+                commands.push(command);
+                offset += len;
+            }
+            Ok(None) => return Ok(commands),
+        }
+    }
+}
+
+/// Create a new log file at `path` containing only the magic bytes.
+pub(crate) fn create_log(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(MAGIC)?;
+    Ok(file)
+}
+
+/// Create a new segment at `path` containing only the magic bytes, ready to be appended to.
+///
+/// The magic bytes are written to a temporary file first, which is moved into place once synced,
+/// so a crash never leaves a segment with an incomplete header behind.
+pub(crate) fn create_segment(path: &Path) -> Result<File> {
+    let tmp_path = path.with_extension("log.tmp");
+    let file = create_log(&tmp_path)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(file)
+}
+
+/// Check whether a non-empty file at `path` is a log written in the legacy JSON-lines format.
+pub(crate) fn is_legacy(path: &Path) -> Result<bool> {
+    let magic = read_magic(path)?;
+    Ok(!magic.is_empty() && magic != MAGIC)
+}
+
+fn read_magic(path: &Path) -> Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    File::open(path)?
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+/// Rewrite legacy JSON-lines log at `path` into the binary format, in place.
+///
+/// Records get sequence numbers starting with `first_seq`, returns the one following the last,
+/// the history of keys is considered complete only from there on.
+pub(crate) fn migrate_legacy(path: &Path, first_seq: u64) -> Result<u64> {
+    let tmp_path = path.with_extension("migrate");
+    let mut writer = BufWriter::new(create_log(&tmp_path)?);
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = String::new();
+    let mut seq = first_seq;
+    loop {
+        buffer.clear();
+        if reader.read_line(&mut buffer)? == 0 {
+            break; // end of stream
+        }
+        let command: LegacyCommand = serde_json::from_str(&buffer)?;
+        let stamp = Stamp { seq, timestamp: 0 };
+        writer.write_all(&encode(&command.into(), DEFAULT_NAMESPACE, stamp))?;
+        seq += 1;
+    }
+    // there's no telling whether the legacy log had been compacted
+    writer.write_all(&marker(seq))?;
+
+    writer.flush()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(seq)
+}
+
+/// Whether the record at `offset` of the log at `path` that failed to decode with `error` is the
+/// last, partially written one.
+///
+/// That's only the case if the file ends before the header of the record or before the length it
+/// declares. A record that fits into the file but doesn't decode is corruption, as is anything
+/// but a short read or a mismatch.
+pub(crate) fn is_torn_tail(error: &Error, path: &Path, offset: u64) -> Result<bool> {
+    match error {
+        Error::Io(error) if error.kind() == ErrorKind::UnexpectedEof => {}
+        Error::Corruption(_) => {}
+        _ => return Ok(false),
+    }
+
+    let mut file = File::open(path)?;
+    let remaining = file.metadata()?.len().saturating_sub(offset);
+    if remaining < HEADER_LEN as u64 {
+        return Ok(true);
+    }
+    let mut header = [0u8; HEADER_LEN];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header)?;
+    let record_len = HEADER_LEN as u64 + u32_at(&header, 4) as u64 + u32_at(&header, 8) as u64;
+    Ok(remaining < record_len)
+}
+
+/// Like [`Read::read_exact`], but returns `Ok(false)` if stream ended before anything was read.
+fn read_exact_or_eof<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(buffer)
+}
@@ -1,72 +1,1158 @@
-use crate::store::KiwiEngine;
+//! Engine backed by [sled](https://docs.rs/sled).
+//!
+//! Every value is stored behind a tag telling whether it expires, followed by its expiry if so:
+//!
+//! ```text
+//! | 0: u8 | value bytes |  or  | 1: u8 | expires_at: u64 | value bytes |
+//! ```
+//!
+//! `expires_at` is little-endian milliseconds since the Unix epoch. The `meta` tree records the
+//! version of this encoding, a database without one holds plain values written through sled or
+//! before keys could expire, they get encoded when the store is opened.
+//!
+//! Keys with an expiry are also listed in the `expiries` tree, ordered by it, so that the sweeper
+//! finds expired keys without a full scan:
+//!
+//! ```text
+//! key: | expires_at: u64 | key bytes |  value: empty
+//! ```
+//!
+//! `expires_at` is big-endian here. Entries are added before the value is written and only removed
+//! by the sweeper, so one may be stale, the value it's checked against is what counts.
+//!
+//! With [`Retention`] every write is also recorded in the `history` tree, in the same transaction,
+//! under a key that sorts versions of a key by their sequence number:
+//!
+//! ```text
+//! key: | key_len: u32 | key bytes | seq: u64 |  value: | timestamp: u64 | removed: u8 | value bytes |
+//! ```
+//!
+//! `key_len` and `seq` are big-endian, `timestamp` is little-endian milliseconds since the Unix
+//! epoch. The empty key holds the big-endian sequence number the history is complete from.
+//!
+//! Keys of the default namespace live in the default tree of the database, keys of a namespace
+//! opened with [`KiwiEngine::open_tree`] in the tree `namespace/<name>`, with their expiries in
+//! `expiries/<name>` and their versions in `history/<name>`.
+use crate::store::counter;
+use crate::store::expiry::{self, Sweeper};
+use crate::store::{
+    BytesIter, ChangeEvent, Command, CompareAndSwapError, CompareAndSwapResult, KeyVersion,
+    KiwiEngine, KiwiSnapshot, Retention, Watcher, WriteBatch,
+};
 use crate::{Error, Result};
-use sled::Db;
+use sled::transaction::{
+    self, ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    Transactional, TransactionalTree,
+};
+use sled::{Batch, Db, IVec, Tree};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeBounds;
 use std::path::PathBuf;
-use std::str;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Tag of values that never expire.
+const TAG_PLAIN: u8 = 0;
+
+/// Tag of values followed by their expiry.
+const TAG_EXPIRING: u8 = 1;
+
+const EXPIRY_LEN: usize = 8;
+
+/// Name of the tree holding metadata of the store itself.
+const META_TREE: &str = "meta";
+
+/// Key of the version of the encoding of values in the meta tree.
+const FORMAT_KEY: &[u8] = b"format";
+
+/// Current version of the encoding of values.
+const FORMAT_VERSION: u8 = 1;
+
+/// Name of the tree keys of the default namespace are listed in by their expiry.
+const EXPIRIES_TREE: &str = "expiries";
+
+/// Prefix of names of expiry trees of other namespaces, followed by the name of the namespace.
+const EXPIRIES_TREE_PREFIX: &str = "expiries/";
+
+/// Name of the tree versions of keys of the default namespace are recorded in.
+const HISTORY_TREE: &str = "history";
+
+/// Prefix of names of trees of other namespaces, followed by the name of the namespace.
+const NAMESPACE_TREE_PREFIX: &str = "namespace/";
+
+/// Prefix of names of history trees of other namespaces, followed by the name of the namespace.
+const HISTORY_TREE_PREFIX: &str = "history/";
+
+/// Key of the horizon in the history tree, keys of versions are never empty.
+const HORIZON_KEY: &[u8] = b"";
+
+/// Key marking that the history tree missed writes, see [`SledStore::open`]. Keys of versions are
+/// longer than this.
+const GAP_KEY: &[u8] = b"gap";
 
 #[derive(Debug, Clone)]
 pub struct SledStoreInner {
     db: Db,
+    /// Tree of the namespace, the default tree of `db` for the default one.
+    tree: Tree,
+    /// Keys of `tree` that have an expiry, ordered by it.
+    expiries: Tree,
+    history: Option<History>,
+}
+
+/// Versions of keys kept for [`KiwiEngine::history`], see [`SledStore::open_with_retention`].
+#[derive(Debug, Clone)]
+struct History {
+    tree: Tree,
+    retention: Retention,
+    /// Keys that may have versions to drop, by when, so that the sweeper doesn't have to go through
+    /// the whole history, see [`trim_history`].
+    due: Arc<Mutex<Due>>,
+    /// Whether the whole history still has to be gone through once, as it is when opened.
+    unchecked: Arc<AtomicBool>,
+}
+
+/// Keys with the time they are due to be checked at, ordered by it.
+type Due = BTreeSet<(u64, Vec<u8>)>;
+
+impl History {
+    fn new(tree: Tree, retention: Retention) -> Self {
+        History {
+            tree,
+            retention,
+            due: Arc::default(),
+            unchecked: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Have versions of `key` checked at `at`.
+    fn schedule(&self, key: Vec<u8>, at: u64) {
+        self.due
+            .lock()
+            .expect("error acquiring lock")
+            .insert((at, key));
+    }
+
+    /// Take the next key whose versions are due to be checked.
+    fn next_due(&self) -> Option<Vec<u8>> {
+        let mut due = self.due.lock().expect("error acquiring lock");
+        match due.iter().next() {
+            Some((at, _)) if *at <= expiry::now() => due.pop_first().map(|(_, key)| key),
+            _ => None,
+        }
+    }
 }
 
 impl SledStoreInner {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        match self.db.insert(key.as_bytes(), value.as_bytes()) {
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.track_expiry(&key, expires_at)?;
+        if self.history.is_some() {
+            return self.commit(&[], &[Command::Set((key, value, expires_at))]);
+        }
+        match self.tree.insert(key, encode(&value, expires_at)) {
             Ok(_) => Ok(()),
             Err(error) => Err(Error::Sled(error)),
         }
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        match self.db.get(key.as_bytes()) {
-            Ok(result) => match result {
-                Some(value) => Ok(Some(str::from_utf8(&value)?.to_owned())),
-                None => Ok(None),
-            },
+    /// Get a live value together with its expiry, reaping it if it has expired.
+    fn get(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let stored = match self.tree.get(key) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return Ok(None),
+            Err(error) => return Err(Error::Sled(error)),
+        };
+        let (value, expires_at) = decode(&stored)?;
+        if expires_at.is_some_and(expiry::is_expired) {
+            // fails harmlessly if it has been set again in the meantime
+            let _ = self
+                .tree
+                .compare_and_swap(key, Some(&stored), None as Option<&[u8]>)?;
+            return Ok(None);
+        }
+        Ok(Some((value.to_vec(), expires_at)))
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.history.is_some() {
+            return self.commit(&[], &[Command::Remove(key)]);
+        }
+        match self.tree.remove(key) {
+            Ok(_) => Ok(()),
             Err(error) => Err(Error::Sled(error)),
         }
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.db.remove(key.as_bytes()) {
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        if self.history.is_some() {
+            return self.compare_and_swap_recorded(key, expected, new);
+        }
+        let new = new.map(|value| encode(&value, None));
+        loop {
+            let stored = self.tree.get(&key)?;
+            let current = match &stored {
+                Some(stored) => match decode(stored)? {
+                    (_, Some(expires_at)) if expiry::is_expired(expires_at) => None,
+                    (value, _) => Some(value.to_vec()),
+                },
+                None => None,
+            };
+            if current != expected {
+                return Ok(Err(CompareAndSwapError { current }));
+            }
+            // compare stored bytes rather than values, so that the expiry is taken into account
+            match self.tree.compare_and_swap(&key, stored, new.clone())? {
+                Ok(()) => return Ok(Ok(())),
+                // swept in the meantime
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Compare and swap through a transaction, so that the write gets recorded in the history.
+    fn compare_and_swap_recorded(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        loop {
+            let stored = self.get_stored(&key)?;
+            let current = match &stored {
+                Some(stored) => Some(decode(stored)?.0.to_vec()),
+                None => None,
+            };
+            if current != expected {
+                return Ok(Err(CompareAndSwapError { current }));
+            }
+            let command = match new.clone() {
+                Some(value) => Command::Set((key.clone(), value, None)),
+                None if current.is_some() => Command::Remove(key.clone()),
+                None => return Ok(Ok(())),
+            };
+            let read = (key.clone(), stored.map(|stored| stored.to_vec()));
+            match self.commit(&[read], &[command]) {
+                Ok(()) => return Ok(Ok(())),
+                // swept in the meantime
+                Err(Error::Conflict(_)) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn incr_by(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        if self.history.is_some() {
+            return self.incr_by_recorded(key, delta);
+        }
+        let mut outcome = Ok(0);
+        // the closure can't fail, on error it leaves the value as it is and reports through `outcome`
+        self.tree.update_and_fetch(key, |stored| {
+            let (current, expires_at) = match stored.map(decode).transpose() {
+                Ok(Some((_, Some(expires_at)))) if expiry::is_expired(expires_at) => (None, None),
+                Ok(Some((value, expires_at))) => (Some(value), expires_at),
+                Ok(None) => (None, None),
+                Err(error) => {
+                    outcome = Err(error);
+                    return stored.map(<[u8]>::to_vec);
+                }
+            };
+            match counter::increment(current, delta) {
+                Ok((count, value)) => {
+                    outcome = Ok(count);
+                    Some(encode(&value, expires_at))
+                }
+                Err(error) => {
+                    outcome = Err(error);
+                    stored.map(<[u8]>::to_vec)
+                }
+            }
+        })?;
+        outcome
+    }
+
+    /// Increment through a transaction, so that the write gets recorded in the history.
+    fn incr_by_recorded(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        loop {
+            let stored = self.get_stored(&key)?;
+            let (current, expires_at) = match &stored {
+                Some(stored) => {
+                    let (value, expires_at) = decode(stored)?;
+                    (Some(value), expires_at)
+                }
+                None => (None, None),
+            };
+            let (count, value) = counter::increment(current, delta)?;
+            let read = (key.clone(), stored.as_deref().map(<[u8]>::to_vec));
+            match self.commit(&[read], &[Command::Set((key.clone(), value, expires_at))]) {
+                Ok(()) => return Ok(count),
+                // swept in the meantime
+                Err(Error::Conflict(_)) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn apply_batch(&mut self, commands: Vec<Command>) -> Result<()> {
+        self.track_expiries(&commands)?;
+        if self.history.is_some() {
+            return self.commit(&[], &commands);
+        }
+        let mut batch = Batch::default();
+        for command in commands {
+            match command {
+                Command::Set((key, value, expires_at)) => {
+                    batch.insert(key, encode(&value, expires_at))
+                }
+                Command::Remove(key) => batch.remove(key),
+                Command::Batch(_) => unreachable!("batches are never nested"),
+            }
+        }
+        match self.tree.apply_batch(batch) {
             Ok(_) => Ok(()),
             Err(error) => Err(Error::Sled(error)),
         }
     }
+
+    /// List `key` under `expires_at` for the sweeper, ahead of writing the value.
+    fn track_expiry(&self, key: &[u8], expires_at: Option<u64>) -> Result<()> {
+        if let Some(expires_at) = expires_at {
+            self.expiries
+                .insert(encode_expiry_key(key, expires_at), &[])?;
+        }
+        Ok(())
+    }
+
+    fn track_expiries(&self, commands: &[Command]) -> Result<()> {
+        for command in commands {
+            if let Command::Set((key, _, expires_at)) = command {
+                self.track_expiry(key, *expires_at)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stored bytes of a live value, they serve as its version in transactions.
+    fn get_stored(&self, key: &[u8]) -> Result<Option<IVec>> {
+        live(self.tree.get(key)?)
+    }
+
+    /// Apply `commands` in a sled transaction if no key in `reads` has changed, recording them in
+    /// the history if there is one.
+    fn commit(&mut self, reads: &[(Vec<u8>, Option<Vec<u8>>)], commands: &[Command]) -> Result<()> {
+        self.track_expiries(commands)?;
+        let outcome = match &self.history {
+            None => self.tree.transaction(|db| {
+                validate(db, reads)?;
+                apply(db, None, commands)
+            }),
+            Some(history) => {
+                let seqs = commands
+                    .iter()
+                    .map(|_| next_seq(&self.db))
+                    .collect::<Result<Vec<_>>>()?;
+                let recorded = Recorded {
+                    seqs: &seqs,
+                    timestamp: expiry::now(),
+                };
+                (&self.tree, &history.tree).transaction(|(db, tree)| {
+                    validate(db, reads)?;
+                    apply(db, Some((tree, &recorded)), commands)
+                })
+            }
+        };
+        match outcome {
+            Ok(()) => {
+                if let Some(history) = &self.history {
+                    for command in commands {
+                        if let Command::Set((key, _, _)) | Command::Remove(key) = command {
+                            history.schedule(key.clone(), 0);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Err(TransactionError::Abort(error)) => Err(error),
+            Err(TransactionError::Storage(error)) => Err(Error::Sled(error)),
+        }
+    }
+
+    /// Copy all live pairs, writes must be locked out meanwhile for the copy to be consistent.
+    fn snapshot(&self) -> Result<SledStoreSnapshot> {
+        let pairs = self
+            .tree
+            .iter()
+            .filter_map(live_pair)
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        Ok(SledStoreSnapshot {
+            pairs: Arc::new(pairs),
+        })
+    }
+
+    /// Recorded versions of `key`, oldest first, `None` without retention.
+    fn history(&self, key: &[u8]) -> Result<Option<Vec<KeyVersion<Vec<u8>>>>> {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return Ok(None),
+        };
+        history
+            .tree
+            .scan_prefix(history_prefix(key))
+            .map(|entry| {
+                let (history_key, recorded) = entry?;
+                decode_version(&history_key, &recorded)
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Value of `key` as of sequence number `seq`, see [`KiwiEngine::get_at_bytes`].
+    fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return Err(Error::HistoryTruncated(seq)),
+        };
+        let prefix = history_prefix(key);
+        let mut last = prefix.clone();
+        last.extend_from_slice(&seq.to_be_bytes());
+        match history.tree.range(prefix..=last).next_back() {
+            Some(entry) => {
+                let (history_key, recorded) = entry?;
+                Ok(decode_version(&history_key, &recorded)?.value)
+            }
+            None if seq >= horizon(&history.tree)? => Ok(None),
+            None => Err(Error::HistoryTruncated(seq)),
+        }
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> BytesIter {
+        Box::new(self.tree.range(range).filter_map(live_pair))
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> BytesIter {
+        Box::new(self.tree.scan_prefix(prefix).filter_map(live_pair))
+    }
+}
+
+/// Fail the transaction with [`Error::Conflict`] if any key in `reads` has changed.
+fn validate(
+    db: &TransactionalTree,
+    reads: &[(Vec<u8>, Option<Vec<u8>>)],
+) -> ConflictableTransactionResult<(), Error> {
+    for (key, version) in reads {
+        let stored = live(db.get(key)?).map_err(ConflictableTransactionError::Abort)?;
+        if stored.as_deref() != version.as_deref() {
+            return transaction::abort(Error::Conflict(key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Sequence numbers and time of writes being recorded in the history.
+struct Recorded<'a> {
+    /// One per command.
+    seqs: &'a [u64],
+    timestamp: u64,
+}
+
+/// Apply `commands` within a transaction, recording them in `history` if given.
+///
+/// Removing a missing key isn't recorded.
+fn apply(
+    db: &TransactionalTree,
+    history: Option<(&TransactionalTree, &Recorded)>,
+    commands: &[Command],
+) -> ConflictableTransactionResult<(), Error> {
+    for (index, command) in commands.iter().enumerate() {
+        let (key, value) = match command {
+            Command::Set((key, value, expires_at)) => {
+                db.insert(key.as_slice(), encode(value, *expires_at))?;
+                (key, Some(value))
+            }
+            Command::Remove(key) => match db.remove(key.as_slice())? {
+                Some(_) => (key, None),
+                None => continue,
+            },
+            Command::Batch(_) => unreachable!("batches are never nested"),
+        };
+        if let Some((tree, recorded)) = history {
+            let history_key = encode_history_key(key, recorded.seqs[index]);
+            tree.insert(history_key, encode_version(recorded.timestamp, value))?;
+        }
+    }
+    Ok(())
+}
+
+/// Sequence number for a write recorded in the history, sled hands them out starting from zero.
+fn next_seq(db: &Db) -> Result<u64> {
+    Ok(db.generate_id()? + 1)
+}
+
+/// Open the history tree called `name` of the namespace in `tree`, recording every live key as its
+/// first version if the history is new. All of them get the same sequence number, the history is
+/// complete only from there on.
+///
+/// A history that missed writes catches up first, see [`close_gap`].
+fn open_history(db: &Db, tree: &Tree, name: &str) -> Result<Tree> {
+    let history = db.open_tree(name)?;
+    if history.contains_key(HORIZON_KEY)? {
+        if history.contains_key(GAP_KEY)? {
+            close_gap(db, tree, &history)?;
+        }
+        return Ok(history);
+    }
+    // the horizon is written last, a history without one is left over from an interrupted start
+    history.clear()?;
+    let horizon = next_seq(db)?;
+    let timestamp = expiry::now();
+    for pair in tree.iter().filter_map(live_pair) {
+        let (key, value) = pair?;
+        let history_key = encode_history_key(&key, horizon);
+        history.insert(history_key, encode_version(timestamp, Some(&value)))?;
+    }
+    history.insert(HORIZON_KEY, &horizon.to_be_bytes())?;
+    history.flush()?;
+    Ok(history)
+}
+
+/// Record the value of every key of `tree` that differs from its last version in `history` as a
+/// new version, all with the same sequence number, so that the history catches up with writes made
+/// while the store was opened without retention.
+///
+/// No sequence numbers are handed out without retention, so to reads as of any sequence number
+/// these writes all happened at once.
+fn close_gap(db: &Db, tree: &Tree, history: &Tree) -> Result<()> {
+    let seq = next_seq(db)?;
+    let timestamp = expiry::now();
+    for pair in tree.iter() {
+        let (key, stored) = pair?;
+        let value = decode(&stored)?.0.to_vec();
+        let last = match history.scan_prefix(history_prefix(&key)).next_back() {
+            Some(entry) => {
+                let (history_key, recorded) = entry?;
+                decode_version(&history_key, &recorded)?.value
+            }
+            None => None,
+        };
+        if last.as_ref() != Some(&value) {
+            let history_key = encode_history_key(&key, seq);
+            history.insert(history_key, encode_version(timestamp, Some(&value)))?;
+        }
+    }
+    // keys removed in the meantime, the last version of a key comes last
+    let mut removed = Vec::new();
+    let mut versions = history.iter().peekable();
+    while let Some(entry) = versions.next() {
+        let (history_key, recorded) = entry?;
+        if history_key == HORIZON_KEY || history_key == GAP_KEY {
+            continue;
+        }
+        let key = split_history_key(&history_key).0;
+        if let Some(Ok((next, _))) = versions.peek() {
+            if next != GAP_KEY && split_history_key(next).0 == key {
+                continue;
+            }
+        }
+        if decode_version(&history_key, &recorded)?.value.is_some() && !tree.contains_key(key)? {
+            removed.push(key.to_vec());
+        }
+    }
+    for key in removed {
+        history.insert(
+            encode_history_key(&key, seq),
+            encode_version(timestamp, None),
+        )?;
+    }
+    history.remove(GAP_KEY)?;
+    history.flush()?;
+    Ok(())
+}
+
+/// Sequence number the history is complete from.
+fn horizon(tree: &Tree) -> Result<u64> {
+    match tree.get(HORIZON_KEY)? {
+        Some(horizon) => Ok(u64_from_be(&horizon)),
+        None => Ok(0),
+    }
+}
+
+/// Remove all expired keys of a namespace and versions its history doesn't retain anymore.
+fn sweep(inner: &SledStoreInner) -> Result<()> {
+    let now = expiry::now();
+    for entry in inner.expiries.range(..(now + 1).to_be_bytes()) {
+        let (expiry_key, _) = entry?;
+        let (expires_at, key) = split_expiry_key(&expiry_key);
+        if let Some(stored) = inner.tree.get(key)? {
+            // the key may have been set again since
+            if decode(&stored)?.1 == Some(expires_at) {
+                let _ = inner
+                    .tree
+                    .compare_and_swap(key, Some(stored), None as Option<&[u8]>)?;
+            }
+        }
+        inner.expiries.remove(&expiry_key)?;
+    }
+    if let Some(history) = &inner.history {
+        trim_history(&inner.db, &inner.tree, history)?;
+    }
+    Ok(())
+}
+
+/// Drop versions of keys in `tree` that `history` doesn't retain, like compaction does for
+/// [`KiwiStore`](crate::KiwiStore).
+///
+/// The whole history is gone through only once, later on just keys that were written since or
+/// have versions that have aged out of `retention` in the meantime.
+fn trim_history(db: &Db, tree: &Tree, history: &History) -> Result<()> {
+    let mut trimmed = false;
+    if history.unchecked.swap(false, Ordering::SeqCst) {
+        // versions of a single key with their timestamps, oldest first
+        let mut versions: Vec<(IVec, u64)> = Vec::new();
+        for entry in history.tree.iter() {
+            let (history_key, recorded) = entry?;
+            if history_key == HORIZON_KEY || history_key == GAP_KEY {
+                continue;
+            }
+            if let Some((last, _)) = versions.last() {
+                if split_history_key(last).0 != split_history_key(&history_key).0 {
+                    trimmed |= trim_versions(tree, history, &mut versions)?;
+                }
+            }
+            versions.push((history_key, u64_from_le(&recorded)));
+        }
+        trimmed |= trim_versions(tree, history, &mut versions)?;
+    }
+    while let Some(key) = history.next_due() {
+        let mut versions = history
+            .tree
+            .scan_prefix(history_prefix(&key))
+            .map(|entry| {
+                let (history_key, recorded) = entry?;
+                Ok((history_key, u64_from_le(&recorded)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        trimmed |= trim_versions(tree, history, &mut versions)?;
+    }
+
+    if trimmed {
+        // versions written up to now may be gone, see `KiwiEngine::get_at_bytes`
+        let horizon = next_seq(db)?;
+        history.tree.fetch_and_update(HORIZON_KEY, |old| {
+            let old = old.map_or(0, u64_from_be);
+            Some(old.max(horizon).to_be_bytes().to_vec())
+        })?;
+    }
+    Ok(())
 }
 
+/// Drop `versions` of a single key that `history` doesn't retain and clear them, returns whether
+/// any got dropped.
+///
+/// Versions are kept from the newest one back to the first one not retained, the newest one is
+/// always kept while the key is live. If versions are kept only for their age, the key is
+/// scheduled to be checked again once the first of them ages out.
+fn trim_versions(tree: &Tree, history: &History, versions: &mut Vec<(IVec, u64)>) -> Result<bool> {
+    let live = match versions.last() {
+        Some((history_key, _)) => live(tree.get(split_history_key(history_key).0)?)?.is_some(),
+        None => return Ok(false),
+    };
+    let retention = history.retention;
+    let kept = versions
+        .iter()
+        .rev()
+        .enumerate()
+        .take_while(|&(newer, &(_, timestamp))| {
+            (newer == 0 && live) || retention.keeps(newer, timestamp)
+        })
+        .count();
+    let dropped = versions.len() - kept;
+
+    let aged_out = versions
+        .iter()
+        .rev()
+        .take(kept)
+        .enumerate()
+        // the newest live version and ones kept by count stay until the key is written again
+        .filter(|&(newer, _)| {
+            !(newer == 0 && live || retention.versions.is_some_and(|versions| newer < versions))
+        })
+        .filter_map(|(_, &(_, timestamp))| {
+            let age = retention.age?.as_millis() as u64;
+            Some(timestamp.saturating_add(age))
+        })
+        .min();
+    if let (Some(at), Some((history_key, _))) = (aged_out, versions.last()) {
+        history.schedule(split_history_key(history_key).0.to_vec(), at);
+    }
+
+    for (history_key, _) in versions.drain(..dropped) {
+        history.tree.remove(history_key)?;
+    }
+    versions.clear();
+    Ok(dropped > 0)
+}
+
+/// Treat stored bytes of an expired key as missing.
+fn live(stored: Option<IVec>) -> Result<Option<IVec>> {
+    match stored {
+        Some(stored) if decode(&stored)?.1.is_some_and(expiry::is_expired) => Ok(None),
+        stored => Ok(stored),
+    }
+}
+
+fn encode_expiry_key(key: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut expiry_key = Vec::with_capacity(EXPIRY_LEN + key.len());
+    expiry_key.extend_from_slice(&expires_at.to_be_bytes());
+    expiry_key.extend_from_slice(key);
+    expiry_key
+}
+
+/// Split a key of the expiries tree into the expiry and the key.
+fn split_expiry_key(expiry_key: &[u8]) -> (u64, &[u8]) {
+    let (expires_at, key) = expiry_key.split_at(EXPIRY_LEN);
+    (u64_from_be(expires_at), key)
+}
+
+/// Prefix of the history keys of all versions of `key`.
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + key.len() + 8);
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+fn encode_history_key(key: &[u8], seq: u64) -> Vec<u8> {
+    let mut history_key = history_prefix(key);
+    history_key.extend_from_slice(&seq.to_be_bytes());
+    history_key
+}
+
+/// Split a history key into the key and the sequence number.
+fn split_history_key(history_key: &[u8]) -> (&[u8], u64) {
+    let (key, seq) = history_key[4..].split_at(history_key.len() - 4 - 8);
+    (key, u64_from_be(seq))
+}
+
+fn encode_version(timestamp: u64, value: Option<&Vec<u8>>) -> Vec<u8> {
+    let bytes = value.map_or(&[][..], Vec::as_slice);
+    let mut recorded = Vec::with_capacity(8 + 1 + bytes.len());
+    recorded.extend_from_slice(&timestamp.to_le_bytes());
+    recorded.push(value.is_none() as u8);
+    recorded.extend_from_slice(bytes);
+    recorded
+}
+
+fn decode_version(history_key: &[u8], recorded: &[u8]) -> Result<KeyVersion<Vec<u8>>> {
+    if history_key.len() < 4 + 8 || recorded.len() < 8 + 1 {
+        return Err(Error::Other("corrupted version in history".to_owned()));
+    }
+    let (_, seq) = split_history_key(history_key);
+    let value = match recorded[8] {
+        0 => Some(recorded[9..].to_vec()),
+        _ => None,
+    };
+    Ok(KeyVersion::new(seq, u64_from_le(recorded), value))
+}
+
+fn u64_from_be(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buffer)
+}
+
+fn u64_from_le(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buffer)
+}
+
+fn encode(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(1 + EXPIRY_LEN + value.len());
+    match expires_at {
+        Some(expires_at) => {
+            stored.push(TAG_EXPIRING);
+            stored.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => stored.push(TAG_PLAIN),
+    }
+    stored.extend_from_slice(value);
+    stored
+}
+
+fn decode(stored: &[u8]) -> Result<(&[u8], Option<u64>)> {
+    match stored.split_first() {
+        Some((&TAG_PLAIN, value)) => Ok((value, None)),
+        Some((&TAG_EXPIRING, rest)) if rest.len() >= EXPIRY_LEN => {
+            let (expiry, value) = rest.split_at(EXPIRY_LEN);
+            Ok((value, Some(u64_from_le(expiry))))
+        }
+        _ => Err(Error::Other(
+            "value stored in an unknown encoding".to_owned(),
+        )),
+    }
+}
+
+/// Open the database at `path`, encoding its values first if they are still plain, see the
+/// [module docs](self).
+fn open_db(path: PathBuf) -> Result<Db> {
+    let db = sled::open(path)?;
+    let meta = db.open_tree(META_TREE)?;
+    if !meta.contains_key(FORMAT_KEY)? {
+        migrate(&db, &meta)?;
+    }
+    Ok(db)
+}
+
+/// Encode all plain values of the default tree and record the version of the encoding, in a single
+/// transaction so that a crash can't leave values encoded twice.
+///
+/// Namespaces didn't exist before values were encoded, so there are no other trees to migrate.
+fn migrate(db: &Db, meta: &Tree) -> Result<()> {
+    let pairs = db.iter().collect::<sled::Result<Vec<_>>>()?;
+    let outcome = (&**db, meta).transaction(|(tree, meta)| {
+        for (key, value) in &pairs {
+            tree.insert(key, encode(value, None))?;
+        }
+        meta.insert(FORMAT_KEY, &[FORMAT_VERSION])?;
+        Ok::<_, ConflictableTransactionError<Error>>(())
+    });
+    match outcome {
+        Ok(()) => Ok(()),
+        Err(TransactionError::Abort(error)) => Err(error),
+        Err(TransactionError::Storage(error)) => Err(Error::Sled(error)),
+    }
+}
+
+/// Decode a scanned pair, skipping it if it has expired.
+fn live_pair(pair: sled::Result<(IVec, IVec)>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+    let (key, stored) = match pair {
+        Ok(pair) => pair,
+        Err(error) => return Some(Err(Error::Sled(error))),
+    };
+    match decode(&stored) {
+        Ok((_, Some(expires_at))) if expiry::is_expired(expires_at) => None,
+        Ok((value, _)) => Some(Ok((key.to_vec(), value.to_vec()))),
+        Err(error) => Some(Err(error)),
+    }
+}
+
+/// Event of the main tree as a [`ChangeEvent`] numbered `seq`, skipping values that can't be decoded.
+fn change_event(event: sled::Event, seq: u64) -> Option<ChangeEvent> {
+    match event {
+        sled::Event::Insert { key, value } => Some(ChangeEvent::Set {
+            key: key.to_vec(),
+            value: decode(&value).ok()?.0.to_vec(),
+            seq,
+        }),
+        sled::Event::Remove { key } => Some(ChangeEvent::Remove {
+            key: key.to_vec(),
+            seq,
+        }),
+    }
+}
+
+/// Read-only view of a [`SledStore`] as of the moment it was taken.
+///
+/// sled has no snapshots of its own, so all live pairs are copied into memory.
+#[derive(Debug, Clone)]
+pub struct SledStoreSnapshot {
+    pairs: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl SledStoreSnapshot {
+    fn collect<'a>(pairs: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>) -> BytesIter {
+        let pairs = pairs
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect::<Vec<_>>();
+        Box::new(pairs.into_iter())
+    }
+}
+
+impl KiwiSnapshot for SledStoreSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(key).cloned())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter> {
+        Ok(Self::collect(self.pairs.range(range)))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter> {
+        Ok(Self::collect(
+            self.pairs
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix)),
+        ))
+    }
+}
+
+/// Namespaces opened with [`KiwiEngine::open_tree`], by name.
+type Namespaces = Arc<Mutex<HashMap<String, Arc<RwLock<SledStoreInner>>>>>;
+
 #[derive(Debug, Clone)]
 pub struct SledStore {
+    /// Namespace of this handle.
     inner: Arc<RwLock<SledStoreInner>>,
+    /// Shared by handles of all namespaces of the store.
+    namespaces: Namespaces,
+    /// Removes expired keys in the background, stopped once the last handle is dropped.
+    _sweeper: Arc<Sweeper>,
 }
 
 impl SledStore {
+    /// Open a store that keeps current values only.
+    ///
+    /// A history recorded before is left in place but misses writes from now on, it catches up with
+    /// them once the store is opened with [`SledStore::open_with_retention`] again.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let db = open_db(path.into())?;
+        for name in db.tree_names() {
+            if name == HISTORY_TREE || name.starts_with(HISTORY_TREE_PREFIX.as_bytes()) {
+                db.open_tree(name)?.insert(GAP_KEY, &[])?;
+            }
+        }
+        Self::start(db, None)
+    }
+
+    /// Open a store that records every write and keeps past versions according to `retention`,
+    /// see [`KiwiEngine::history`]. Versions beyond `retention` are dropped in the background.
+    ///
+    /// When opened this way for the first time, the history starts with the current values.
+    pub fn open_with_retention(path: impl Into<PathBuf>, retention: Retention) -> Result<Self> {
+        let db = open_db(path.into())?;
+        let tree = open_history(&db, &db, HISTORY_TREE)?;
+        Self::start(db, Some(History::new(tree, retention)))
+    }
+
+    fn start(db: Db, history: Option<History>) -> Result<Self> {
+        let tree = Tree::clone(&db);
+        let expiries = db.open_tree(EXPIRIES_TREE)?;
+        let inner = SledStoreInner {
+            db,
+            tree,
+            expiries,
+            history,
+        };
+        let namespaces = Namespaces::default();
+        let sweep_default = inner.clone();
+        let sweep_namespaces = Arc::clone(&namespaces);
         Ok(SledStore {
-            inner: Arc::new(RwLock::new(SledStoreInner {
-                db: sled::open(path.into())?,
+            inner: Arc::new(RwLock::new(inner)),
+            namespaces,
+            _sweeper: Arc::new(Sweeper::start(expiry::SWEEP_INTERVAL, move || {
+                sweep(&sweep_default)?;
+                let opened: Vec<SledStoreInner> = sweep_namespaces
+                    .lock()
+                    .expect("error acquiring lock")
+                    .values()
+                    .map(|inner| inner.read().expect("error acquiring lock").clone())
+                    .collect();
+                for inner in &opened {
+                    sweep(inner)?;
+                }
+                Ok(true)
             })),
         })
     }
+
+    /// Open trees of the namespace called `name`, with a history if the store keeps one.
+    fn open_namespace(&self, name: &str) -> Result<SledStoreInner> {
+        let inner = self.inner.read().expect("error acquiring lock");
+        let db = inner.db.clone();
+        let tree = db.open_tree(format!("{}{}", NAMESPACE_TREE_PREFIX, name))?;
+        let expiries = db.open_tree(format!("{}{}", EXPIRIES_TREE_PREFIX, name))?;
+        let history = match &inner.history {
+            Some(history) => {
+                let name = format!("{}{}", HISTORY_TREE_PREFIX, name);
+                let tree = open_history(&db, &tree, &name)?;
+                Some(History::new(tree, history.retention))
+            }
+            None => None,
+        };
+        Ok(SledStoreInner {
+            db,
+            tree,
+            expiries,
+            history,
+        })
+    }
 }
 
 impl KiwiEngine for SledStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
+    type Snapshot = SledStoreSnapshot;
+    /// Stored bytes of the key, value and expiry, so rewriting the same value isn't a conflict.
+    type Version = Option<Vec<u8>>;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         self.inner
             .write()
             .expect("error acquiring lock")
-            .set(key, value)
+            .set(key, value, None)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        self.inner.read().expect("error acquiring lock").get(key)
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.inner.write().expect("error acquiring lock").set(
+            key,
+            value,
+            Some(expiry::expires_at(ttl)),
+        )
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let found = self.inner.read().expect("error acquiring lock").get(key)?;
+        Ok(found.map(|(value, _)| value))
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let found = self.inner.read().expect("error acquiring lock").get(key)?;
+        Ok(found
+            .and_then(|(_, expires_at)| expires_at)
+            .map(expiry::remaining))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
         self.inner
             .write()
             .expect("error acquiring lock")
             .remove(key)
     }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        self.inner
+            .write()
+            .expect("error acquiring lock")
+            .compare_and_swap(key, expected, new)
+    }
+
+    fn incr_by_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.inner
+            .write()
+            .expect("error acquiring lock")
+            .incr_by(key, delta)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.inner
+            .write()
+            .expect("error acquiring lock")
+            .apply_batch(batch.into_commands())
+    }
+
+    /// Copies the whole store under the write lock, blocking writes until it's done.
+    fn snapshot(&self) -> Result<SledStoreSnapshot> {
+        self.inner.write().expect("error acquiring lock").snapshot()
+    }
+
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Self::Version)> {
+        let stored = self
+            .inner
+            .read()
+            .expect("error acquiring lock")
+            .get_stored(key)?;
+        let value = match &stored {
+            Some(stored) => Some(decode(stored)?.0.to_vec()),
+            None => None,
+        };
+        Ok((value, stored.map(|stored| stored.to_vec())))
+    }
+
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        self.inner
+            .write()
+            .expect("error acquiring lock")
+            .commit(&reads, &batch.into_commands())
+    }
+
+    /// Recorded versions, empty unless opened with [`SledStore::open_with_retention`].
+    fn history_bytes(&self, key: &[u8]) -> Result<Vec<KeyVersion<Vec<u8>>>> {
+        let history = self
+            .inner
+            .read()
+            .expect("error acquiring lock")
+            .history(key)?;
+        Ok(history.unwrap_or_default())
+    }
+
+    /// Fails unless opened with [`SledStore::open_with_retention`].
+    fn get_at_bytes(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .read()
+            .expect("error acquiring lock")
+            .get_at(key, seq)
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter> {
+        Ok(self.inner.read().expect("error acquiring lock").scan(range))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesIter> {
+        Ok(self
+            .inner
+            .read()
+            .expect("error acquiring lock")
+            .scan_prefix(prefix))
+    }
+
+    /// Subscribe with [`sled::Tree::watch_prefix`]. sled has no sequence numbers of its own, so
+    /// events are numbered per watcher, and expired keys are reported when swept.
+    fn watch_bytes(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        let subscriber = self
+            .inner
+            .read()
+            .expect("error acquiring lock")
+            .tree
+            .watch_prefix(prefix);
+        Ok(Watcher::sled(subscriber, change_event))
+    }
+
+    /// Open the namespace as a tree of its own, see [`sled::Db::open_tree`].
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        let mut namespaces = self.namespaces.lock().expect("error acquiring lock");
+        let inner = match namespaces.get(name) {
+            Some(inner) => Arc::clone(inner),
+            None => {
+                let inner = Arc::new(RwLock::new(self.open_namespace(name)?));
+                namespaces.insert(name.to_owned(), Arc::clone(&inner));
+                inner
+            }
+        };
+        Ok(SledStore {
+            inner,
+            ..self.clone()
+        })
+    }
+
+    fn find_tree(&self, name: &str) -> Result<Option<Self>> {
+        let opened = self
+            .namespaces
+            .lock()
+            .expect("error acquiring lock")
+            .contains_key(name);
+        // trees of namespaces opened before the store was last closed are only known to sled
+        let tree_name = format!("{}{}", NAMESPACE_TREE_PREFIX, name);
+        let stored = || {
+            let inner = self.inner.read().expect("error acquiring lock");
+            let names = inner.db.tree_names();
+            names.iter().any(|stored| stored == tree_name.as_bytes())
+        };
+        if opened || stored() {
+            self.open_tree(name).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
 }
@@ -0,0 +1,116 @@
+use crate::store::{into_string, KiwiEngine, WriteBatch};
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Version and value of a key read from the store.
+type Read<V> = (V, Option<Vec<u8>>);
+
+/// Read-modify-write transaction over several keys, started by [`KiwiEngine::begin`].
+///
+/// Reads go straight to the store and remember the version of every key read, writes are buffered
+/// until [`Transaction::commit`]. Commit applies all writes atomically if none of the keys read has
+/// changed in the meantime, otherwise it fails with [`Error::Conflict`](crate::Error::Conflict)
+/// and nothing is written, the transaction can then be retried from the start.
+///
+/// Reads see the transaction's own writes and return the same value when repeated.
+/// # Example
+/// ```
+/// # use std::error::Error;
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// # let some_dir = TempDir::new().unwrap();
+/// use kiwi_store::{KiwiEngine, KiwiStore};
+/// let store = KiwiStore::open(some_dir.path())?;
+/// store.set("alice".to_owned(), "10".to_owned())?;
+///
+/// let mut transaction = store.begin();
+/// let alice: u32 = transaction.get("alice".to_owned())?.unwrap().parse()?;
+/// transaction.set("alice".to_owned(), (alice - 3).to_string());
+/// transaction.set("bob".to_owned(), "3".to_owned());
+/// transaction.commit()?;
+///
+/// assert_eq!(Some("7".to_owned()), store.get("alice".to_owned())?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<E: KiwiEngine> {
+    engine: E,
+    reads: BTreeMap<Vec<u8>, Read<E::Version>>,
+    /// Value of every key written, `None` for removed ones.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KiwiEngine> Transaction<E> {
+    pub(super) fn new(engine: E) -> Self {
+        Transaction {
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some((_, value)) = self.reads.get(key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.engine.get_versioned(key)?;
+        self.reads.insert(key.to_vec(), (version, value.clone()));
+        Ok(value)
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove `key`, removing a key that doesn't exist isn't an error within a transaction.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(into_string).transpose()
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Apply all writes if none of the keys read has changed since.
+    pub fn commit(self) -> Result<()> {
+        if self.reads.is_empty() && self.writes.is_empty() {
+            return Ok(());
+        }
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|(key, (version, _))| (key, version))
+            .collect();
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set_bytes(key, value),
+                None => batch.remove_bytes(key),
+            }
+        }
+        self.engine.commit_transaction(reads, batch)
+    }
+}
+
+impl<E: KiwiEngine + fmt::Debug> fmt::Debug for Transaction<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("engine", &self.engine)
+            .field("reads", &self.reads.len())
+            .field("writes", &self.writes.len())
+            .finish()
+    }
+}
@@ -0,0 +1,99 @@
+use crate::codec::{Codec, Json};
+use crate::store::KiwiEngine;
+use crate::Result;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// Typed view of any [`KiwiEngine`], keys and values are serialized with codec `C`.
+/// # Example
+/// ```
+/// # use std::error::Error;
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// # let some_dir = TempDir::new().unwrap();
+/// use kiwi_store::{KiwiStore, TypedStore};
+/// let store: TypedStore<_, u64, Vec<String>> = TypedStore::new(KiwiStore::open(some_dir.path())?);
+///
+/// store.set(&1, &vec!["value1".to_owned()])?;
+/// assert_eq!(Some(vec!["value1".to_owned()]), store.get(&1)?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct TypedStore<E, K, V, C = Json> {
+    engine: E,
+    // function pointers keep the store `Send` and `Sync` whatever the types are, they're never held
+    types: PhantomData<fn(K) -> V>,
+    codec: PhantomData<fn() -> C>,
+}
+
+impl<E, K, V, C> TypedStore<E, K, V, C>
+where
+    E: KiwiEngine,
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn new(engine: E) -> Self {
+        TypedStore {
+            engine,
+            types: PhantomData,
+            codec: PhantomData,
+        }
+    }
+
+    /// Set a value. Overrides the value if key is already present
+    pub fn set(&self, key: &K, value: &V) -> Result<()> {
+        self.engine.set_bytes(C::encode(key)?, C::encode(value)?)
+    }
+
+    /// Set a value that expires after `ttl`.
+    pub fn set_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        self.engine
+            .set_bytes_with_ttl(C::encode(key)?, C::encode(value)?, ttl)
+    }
+
+    /// Get a value, fails with [`crate::Error::Codec`] if the stored bytes aren't a `V`.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.engine.get_bytes(&C::encode(key)?)? {
+            Some(bytes) => Ok(Some(C::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a value, fails with [`crate::Error::NoKey`] if it's not present.
+    pub fn remove(&self, key: &K) -> Result<()> {
+        self.engine.remove_bytes(C::encode(key)?)
+    }
+
+    /// Time left until `key` expires, `None` if it doesn't exist or never expires.
+    pub fn ttl(&self, key: &K) -> Result<Option<Duration>> {
+        self.engine.ttl_bytes(&C::encode(key)?)
+    }
+
+    /// Underlying engine, for access to raw bytes.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+}
+
+impl<E: Clone, K, V, C> Clone for TypedStore<E, K, V, C> {
+    fn clone(&self) -> Self {
+        TypedStore {
+            engine: self.engine.clone(),
+            types: PhantomData,
+            codec: PhantomData,
+        }
+    }
+}
+
+impl<E: fmt::Debug, K, V, C> fmt::Debug for TypedStore<E, K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedStore")
+            .field("engine", &self.engine)
+            .finish()
+    }
+}
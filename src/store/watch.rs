@@ -0,0 +1,173 @@
+//! Subscriptions to changes of keys, see [`KiwiEngine::watch`](crate::KiwiEngine::watch).
+use crate::store::Command;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use std::fmt;
+use std::time::Duration;
+
+/// Change of a key, delivered by a [`Watcher`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        seq: u64,
+    },
+    Remove {
+        key: Vec<u8>,
+        seq: u64,
+    },
+}
+
+impl ChangeEvent {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            ChangeEvent::Set { key, .. } | ChangeEvent::Remove { key, .. } => key,
+        }
+    }
+
+    /// Sequence number of the change, higher for every later change delivered by a watcher.
+    pub fn seq(&self) -> u64 {
+        match self {
+            ChangeEvent::Set { seq, .. } | ChangeEvent::Remove { seq, .. } => *seq,
+        }
+    }
+}
+
+/// Blocking iterator over changes of keys starting with a prefix, returned by
+/// [`KiwiEngine::watch_bytes`](crate::KiwiEngine::watch_bytes).
+///
+/// Only changes made after the watcher was created are delivered, in the order they were applied.
+/// Whether keys that expire are reported depends on the engine. Iteration ends once the store is
+/// closed, changes not consumed yet are buffered without limit.
+pub struct Watcher {
+    source: Source,
+}
+
+enum Source {
+    Channel(Receiver<ChangeEvent>),
+    /// Sled doesn't expose sequence numbers, events are numbered in the order they arrive.
+    Sled {
+        subscriber: sled::Subscriber,
+        decode: fn(sled::Event, u64) -> Option<ChangeEvent>,
+        next_seq: u64,
+    },
+}
+
+impl Watcher {
+    pub(crate) fn sled(
+        subscriber: sled::Subscriber,
+        decode: fn(sled::Event, u64) -> Option<ChangeEvent>,
+    ) -> Self {
+        Watcher {
+            source: Source::Sled {
+                subscriber,
+                decode,
+                next_seq: 1,
+            },
+        }
+    }
+
+    /// Wait at most `timeout` for the next change, `None` if there was none or the store is closed.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<ChangeEvent> {
+        match &mut self.source {
+            Source::Channel(receiver) => match receiver.recv_timeout(timeout) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+            },
+            Source::Sled {
+                subscriber,
+                decode,
+                next_seq,
+            } => loop {
+                let event = subscriber.next_timeout(timeout).ok()?;
+                if let Some(event) = decode(event, *next_seq) {
+                    *next_seq += 1;
+                    return Some(event);
+                }
+            },
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<ChangeEvent> {
+        match &mut self.source {
+            Source::Channel(receiver) => receiver.recv().ok(),
+            Source::Sled {
+                subscriber,
+                decode,
+                next_seq,
+            } => loop {
+                if let Some(event) = decode(subscriber.next()?, *next_seq) {
+                    *next_seq += 1;
+                    return Some(event);
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source {
+            Source::Channel(_) => "channel",
+            Source::Sled { .. } => "sled",
+        };
+        f.debug_struct("Watcher").field("source", &source).finish()
+    }
+}
+
+/// Watchers of an engine that publishes its own changes.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    /// Namespace and key prefix every watcher is interested in.
+    watchers: Vec<(u32, Vec<u8>, Sender<ChangeEvent>)>,
+}
+
+impl Watchers {
+    pub(crate) fn watch(&mut self, namespace: u32, prefix: Vec<u8>) -> Watcher {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.watchers.push((namespace, prefix, sender));
+        Watcher {
+            source: Source::Channel(receiver),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+
+    /// Deliver changes made by `command` on keys of `namespace`, written with sequence numbers
+    /// from `seq` on.
+    ///
+    /// Watchers that were dropped are forgotten.
+    pub(crate) fn publish(&mut self, namespace: u32, command: &Command, seq: u64) {
+        for event in changes(command.clone(), seq) {
+            self.send(namespace, event);
+        }
+    }
+
+    fn send(&mut self, namespace: u32, event: ChangeEvent) {
+        self.watchers.retain(|(watched, prefix, sender)| {
+            *watched != namespace
+                || !event.key().starts_with(prefix)
+                || sender.send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// Changes made by `command`, written with sequence numbers from `seq` on.
+pub(crate) fn changes(command: Command, seq: u64) -> Vec<ChangeEvent> {
+    match command {
+        Command::Set((key, value, _)) => vec![ChangeEvent::Set { key, value, seq }],
+        Command::Remove(key) => vec![ChangeEvent::Remove { key, seq }],
+        Command::Batch(commands) => commands
+            .into_iter()
+            .zip(seq..)
+            .flat_map(|(command, seq)| changes(command, seq))
+            .collect(),
+    }
+}
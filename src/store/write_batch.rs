@@ -0,0 +1,79 @@
+use crate::store::expiry;
+use crate::store::Command;
+
+use std::time::Duration;
+
+/// Sets and removes applied all at once by [`KiwiEngine::apply_batch`](crate::KiwiEngine::apply_batch).
+///
+/// Either all of them end up in the store or none of them, also across a crash. They are applied in
+/// the order they were added, removing a key that doesn't exist isn't an error within a batch.
+/// # Example
+/// ```
+/// # use std::error::Error;
+/// # use tempfile::TempDir;
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// # let some_dir = TempDir::new().unwrap();
+/// use kiwi_store::{KiwiEngine, KiwiStore, WriteBatch};
+/// let store = KiwiStore::open(some_dir.path())?;
+/// store.set("key1".to_owned(), "value1".to_owned())?;
+///
+/// let mut batch = WriteBatch::new();
+/// batch.set("key2".to_owned(), "value2".to_owned());
+/// batch.remove("key1".to_owned());
+/// store.apply_batch(batch)?;
+///
+/// assert_eq!(None, store.get("key1".to_owned())?);
+/// assert_eq!(Some("value2".to_owned()), store.get("key2".to_owned())?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.commands.push(Command::Set((key, value, None)));
+    }
+
+    /// Set a value that expires `ttl` after the batch is created.
+    pub fn set_bytes_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        let expires_at = expiry::expires_at(ttl);
+        self.commands
+            .push(Command::Set((key, value, Some(expires_at))));
+    }
+
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.commands.push(Command::Remove(key));
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Number of sets and removes in the batch.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub(super) fn into_commands(self) -> Vec<Command> {
+        self.commands
+    }
+}
@@ -40,6 +40,7 @@ impl ThreadPool for NaiveThreadPool {
     }
 }
 
+#[derive(Debug)]
 pub struct SharedQueueThreadPool {
     queue: Sender<Box<dyn FnOnce() + Send + 'static>>,
 }
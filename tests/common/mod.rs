@@ -0,0 +1,268 @@
+//! Helpers and test bodies shared by the tests of all engines.
+// each test crate uses only some of them
+#![allow(dead_code)]
+
+use kiwi_store::{
+    CompareAndSwapError, Error, KiwiEngine, KiwiSnapshot, KvIter, Result, Transaction,
+};
+use std::thread;
+use std::time::Duration;
+
+pub fn collect(iter: KvIter) -> Result<Vec<(String, String)>> {
+    iter.collect()
+}
+
+pub fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// Scans should return keys in order, bounded by range or prefix
+pub fn scan_range_and_prefix<E: KiwiEngine>(store: E) -> Result<()> {
+    for key in &["b", "user:2", "a", "user:1", "usera", "c"] {
+        store.set(key.to_string(), format!("v-{}", key))?;
+    }
+    store.remove("b".to_owned())?;
+
+    assert_eq!(
+        collect(store.scan("a".to_owned()..="c".to_owned())?)?,
+        pairs(&[("a", "v-a"), ("c", "v-c")])
+    );
+    assert_eq!(
+        collect(store.scan(.."user:2".to_owned())?)?,
+        pairs(&[("a", "v-a"), ("c", "v-c"), ("user:1", "v-user:1")])
+    );
+    assert_eq!(
+        collect(store.scan_prefix("user:".to_owned())?)?,
+        pairs(&[("user:1", "v-user:1"), ("user:2", "v-user:2")])
+    );
+
+    Ok(())
+}
+
+// Should store keys and values that aren't valid UTF-8
+pub fn binary_keys_and_values<E: KiwiEngine>(store: E) -> Result<()> {
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x89, b'P', b'N', b'G', 0x00, 0xff];
+    store.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(
+        store
+            .scan_prefix_bytes(vec![0xff])?
+            .collect::<Result<Vec<_>>>()?,
+        vec![(key.clone(), value.clone())]
+    );
+
+    // the string API refuses data that isn't UTF-8
+    store.set_bytes(b"image".to_vec(), value)?;
+    assert!(matches!(
+        store.get("image".to_owned()),
+        Err(Error::Utf8Error(_))
+    ));
+
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+pub fn compare_and_swap<E: KiwiEngine>(store: E) -> Result<()> {
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?,
+        Err(CompareAndSwapError {
+            current: Some("value1".to_owned())
+        })
+    );
+    assert_eq!(
+        store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned())
+        )?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // expired keys count as missing
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        store.compare_and_swap("key2".to_owned(), Some("value2".to_owned()), None)?,
+        Err(CompareAndSwapError { current: None })
+    );
+    assert_eq!(
+        store.compare_and_swap("key2".to_owned(), None, Some("value2b".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?,
+        Ok(())
+    );
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("key2", "value2b")]));
+
+    Ok(())
+}
+
+pub fn incr_by<E: KiwiEngine>(store: E) -> Result<()> {
+    assert_eq!(store.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr_by("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    // expiry is kept, but an expired counter starts over
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert_eq!(store.incr_by("expiring".to_owned(), 1)?, 2);
+    assert!(store.ttl("expiring".to_owned())?.is_some());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.incr_by("expiring".to_owned(), 1)?, 1);
+    assert_eq!(store.ttl("expiring".to_owned())?, None);
+
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.incr_by("text".to_owned(), 1),
+        Err(Error::InvalidCounter(_))
+    ));
+    assert_eq!(store.get("text".to_owned())?, Some("value".to_owned()));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr_by("concurrent".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("concurrent".to_owned())?, Some("800".to_owned()));
+
+    Ok(())
+}
+
+pub fn transaction_conflict<E: KiwiEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // changed value
+    let mut transaction = store.begin();
+    transaction.get("key1".to_owned())?;
+    transaction.set("key2".to_owned(), "value2".to_owned());
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    assert!(matches!(transaction.commit(), Err(Error::Conflict(key)) if key == b"key1"));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // created key
+    let mut transaction = store.begin();
+    assert_eq!(transaction.get("missing".to_owned())?, None);
+    transaction.set("key2".to_owned(), "value2".to_owned());
+    store.set("missing".to_owned(), "found".to_owned())?;
+    assert!(matches!(transaction.commit(), Err(Error::Conflict(_))));
+
+    // removed key
+    let mut transaction = store.begin();
+    transaction.get("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(transaction.commit(), Err(Error::Conflict(_))));
+
+    // unrelated and blind writes don't conflict
+    let mut transaction = store.begin();
+    transaction.get("missing".to_owned())?;
+    transaction.set("key3".to_owned(), "value3".to_owned());
+    transaction.remove("key2".to_owned());
+    store.set("key3".to_owned(), "other".to_owned())?;
+    store.set("unrelated".to_owned(), "value".to_owned())?;
+    transaction.commit()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Transfers between accounts retried on conflict should keep the total
+pub fn concurrent_transactions<E: KiwiEngine>(store: E) -> Result<()> {
+    for account in 0..4 {
+        store.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for iter in 0..50 {
+                    let from = format!("account{}", (thread + iter) % 4);
+                    let to = format!("account{}", (thread + iter + 1) % 4);
+                    loop {
+                        let mut transaction = store.begin();
+                        let balance = |transaction: &mut Transaction<_>, key: &String| {
+                            transaction
+                                .get(key.clone())
+                                .unwrap()
+                                .unwrap()
+                                .parse::<i64>()
+                                .unwrap()
+                        };
+                        let from_balance = balance(&mut transaction, &from);
+                        let to_balance = balance(&mut transaction, &to);
+                        transaction.set(from.clone(), (from_balance - 1).to_string());
+                        transaction.set(to.clone(), (to_balance + 1).to_string());
+                        match transaction.commit() {
+                            Ok(()) => break,
+                            Err(Error::Conflict(_)) => continue,
+                            Err(error) => panic!("{}", error),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut total = 0;
+    for (_, balance) in collect(store.scan(..)?)? {
+        total += balance.parse::<i64>().unwrap();
+    }
+    assert_eq!(total, 400);
+
+    Ok(())
+}
+
+pub fn snapshot<E: KiwiEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(
+        collect(snapshot.scan(..)?)?,
+        pairs(&[("key1", "value1"), ("key2", "value2")])
+    );
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        pairs(&[("key1", "value1b"), ("key3", "value3")])
+    );
+
+    Ok(())
+}
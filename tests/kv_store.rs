@@ -1,6 +1,16 @@
-use kiwi_store::{KiwiEngine, KiwiStore, Result};
+mod common;
+
+use common::{collect, pairs};
+use kiwi_store::{
+    ChangeEvent, CompareAndSwapError, Durability, Error, KiwiEngine, KiwiSnapshot, KiwiStore,
+    KiwiStoreOptions, ReadMode, Result, Retention, WriteBatch,
+};
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -95,6 +105,13 @@ fn compaction() -> Result<()> {
                 res.and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
             })
+            // files removed by a compaction running in the background count as gone
+            .filter(|res| match res {
+                Err(error) => {
+                    error.io_error().map(io::Error::kind) != Some(io::ErrorKind::NotFound)
+                }
+                Ok(_) => true,
+            })
             .sum();
         len.expect("fail to get directory size")
     };
@@ -127,6 +144,50 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Reads should be served with correct values while compaction runs in the background
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..10000 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+
+    // overwrite other keys until compaction kicks in a few times
+    for iter in 0..50 {
+        for key_id in 0..1000 {
+            store.set(format!("other{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("other999".to_owned())?, Some("49".to_owned()));
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -158,6 +219,51 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Concurrent writers flushing every write should all be acknowledged and persisted
+#[test]
+fn concurrent_set_and_remove_with_fsync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        durability: Durability::Always,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..50 {
+                let key = format!("key{}_{}", thread_id, i);
+                store.set(key.clone(), format!("value{}", i)).unwrap();
+                if i % 2 == 0 {
+                    store.remove(key.clone()).unwrap();
+                    assert!(store.remove(key).is_err());
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    for thread_id in 0..16 {
+        for i in 0..50 {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}_{}", thread_id, i))?, expected);
+        }
+    }
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +315,1270 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should migrate a log written in the legacy JSON-lines format
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.db"),
+        "{\"Set\":[\"key1\",\"value1\"]}\n\
+         {\"Set\":[\"key2\",\"value2\"]}\n\
+         {\"Remove\":\"key1\"}\n\
+         {\"Set\":[\"key3\",\"with\\nnewline\"]}\n",
+    )?;
+
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get("key3".to_owned())?,
+        Some("with\nnewline".to_owned())
+    );
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should drop a partially written last record instead of failing to open
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // cut the last record in half
+    let db_path = temp_dir.path().join("1.log");
+    let log = OpenOptions::new().write(true).open(&db_path)?;
+    log.set_len(log.metadata()?.len() - 5)?;
+    drop(log);
+
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A segment whose header was never completely written should be ignored
+#[test]
+fn ignore_unfinished_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let tmp_path = temp_dir.path().join("2.log.tmp");
+    fs::write(&tmp_path, b"KI")?;
+
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert!(!tmp_path.exists());
+    assert!(!temp_dir.path().join("2.log").exists());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should report the offset of a corrupted record in the middle of the log
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip last byte of "value2"
+    let db_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&db_path)?;
+    let record_len = (content.len() - 5) / 3;
+    let second_record = 5 + record_len;
+    content[second_record + record_len - 1] ^= 0xff;
+    fs::write(&db_path, content)?;
+
+    match KiwiStore::open(temp_dir.path()) {
+        Err(Error::Corruption(offset)) => assert_eq!(offset, second_record as u64),
+        other => panic!("expected corruption error, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Should report a corrupted length field in the middle of the log instead of truncating the log
+// from there as if it was a torn write
+#[test]
+fn detect_corrupted_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // flip low byte of value_len of the first record, so it still ends within the log
+    let db_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&db_path)?;
+    let len = content.len();
+    content[5 + 8] ^= 0xff;
+    fs::write(&db_path, content)?;
+
+    match KiwiStore::open(temp_dir.path()) {
+        Err(Error::Corruption(offset)) => assert_eq!(offset, 5),
+        other => panic!("expected corruption error, got {:?}", other),
+    }
+    assert_eq!(fs::metadata(&db_path)?.len(), len as u64);
+
+    Ok(())
+}
+
+// Should split the log into segments and read values back from all of them
+#[test]
+fn segmented_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 256,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let segments = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension() == Some(OsStr::new("log"))
+        })
+        .count();
+    assert!(segments > 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Should not rewrite a log that holds only live data, however big it is
+#[test]
+fn no_compaction_of_live_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_garbage_ratio: 0.5,
+        compaction_min_size: 4096,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the first segment would be gone after a compaction
+    assert!(temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// Should compact once overwritten records take more than the configured share of the log
+#[test]
+fn compaction_of_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_garbage_ratio: 0.5,
+        compaction_min_size: 4096,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    assert!(!temp_dir.path().join("1.log").exists());
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// Should rebuild the index from hint files, and fall back to replaying segments with a broken one
+#[test]
+fn open_with_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_garbage_ratio: 0.5,
+        compaction_min_size: 4096,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let hints: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("hint")))
+        .collect();
+    assert!(!hints.is_empty());
+
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    drop(store);
+
+    for hint in hints {
+        let mut content = fs::read(&hint)?;
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        fs::write(&hint, content)?;
+    }
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should serve values from memory-mapped segments, also after they get compacted
+#[test]
+fn mmap_read_mode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_garbage_ratio: 0.5,
+        compaction_min_size: 4096,
+        read_mode: ReadMode::Mmap,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter))
+            );
+        }
+    }
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should persist writes with every durability policy
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::Always,
+        Durability::EveryN(10),
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Never,
+    ];
+    for durability in policies.iter().copied() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KiwiStoreOptions {
+            max_segment_size: 1024,
+            durability,
+            ..KiwiStoreOptions::default()
+        };
+        let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        thread::sleep(Duration::from_millis(20));
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Scans should return live keys in order, bounded by range or prefix
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+
+    for key in &["b", "user:2", "a", "user:1", "user:3", "usera", "c"] {
+        store.set(key.to_string(), format!("v-{}", key))?;
+    }
+    store.set("user:2".to_owned(), "v-user:2b".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(
+        collect(store.scan("a".to_owned().."c".to_owned())?)?,
+        pairs(&[("a", "v-a"), ("b", "v-b")])
+    );
+    assert_eq!(
+        collect(store.scan("user:".to_owned()..)?)?,
+        pairs(&[
+            ("user:1", "v-user:1"),
+            ("user:2", "v-user:2b"),
+            ("usera", "v-usera")
+        ])
+    );
+    assert_eq!(collect(store.scan(..)?)?.len(), 6);
+    assert_eq!(
+        collect(store.scan_prefix("user:".to_owned())?)?,
+        pairs(&[("user:1", "v-user:1"), ("user:2", "v-user:2b")])
+    );
+    assert!(collect(store.scan_prefix("x".to_owned())?)?.is_empty());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(
+        collect(store.scan_prefix("user:".to_owned())?)?,
+        pairs(&[("user:1", "v-user:1"), ("user:2", "v-user:2b")])
+    );
+
+    Ok(())
+}
+
+// Keys removed after a scan started should be skipped
+#[test]
+fn scan_skips_keys_removed_during_iteration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), key.to_string())?;
+    }
+
+    let mut iter = store.scan(..)?;
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("a".to_owned(), "a".to_owned()))
+    );
+    store.remove("b".to_owned())?;
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("c".to_owned(), "c".to_owned()))
+    );
+    assert_eq!(iter.next().transpose()?, None);
+
+    Ok(())
+}
+
+// Should store keys and values that aren't valid UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x89, b'P', b'N', b'G', 0x00, 0xff];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(vec![0xff, 0x01], vec![])?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+
+    // the string API refuses data that isn't UTF-8
+    store.set_bytes(b"image".to_vec(), value.clone())?;
+    assert!(matches!(
+        store.get("image".to_owned()),
+        Err(Error::Utf8Error(_))
+    ));
+    assert!(store.scan(..)?.any(|pair| pair.is_err()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(
+        store
+            .scan_prefix_bytes(vec![0xff])?
+            .collect::<Result<Vec<_>>>()?,
+        vec![(key.clone(), value), (vec![0xff, 0x01], vec![])]
+    );
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+// Expired keys should be invisible to reads, also after reopening the store
+#[test]
+fn keys_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "cached".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(store.get("cached".to_owned())?, Some("value".to_owned()));
+    let ttl = store.ttl("cached".to_owned())?.expect("key expires");
+    assert!(ttl > Duration::ZERO && ttl <= Duration::from_millis(200));
+    assert_eq!(store.ttl("kept".to_owned())?, None);
+
+    // Open from disk again and check persistent expiry
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert!(store.ttl("cached".to_owned())?.is_some());
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("cached".to_owned())?, None);
+    assert_eq!(store.ttl("cached".to_owned())?, None);
+    assert_eq!(store.scan(..)?.count(), 1);
+    assert!(store.remove("cached".to_owned()).is_err());
+
+    // Setting the key again makes it live and drops its expiry
+    store.set("cached".to_owned(), "fresh".to_owned())?;
+    assert_eq!(store.get("cached".to_owned())?, Some("fresh".to_owned()));
+    assert_eq!(store.ttl("cached".to_owned())?, None);
+
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("cached".to_owned())?, Some("fresh".to_owned()));
+    Ok(())
+}
+
+// Compaction should drop expired records
+#[test]
+fn compaction_of_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        sweep_interval: None,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    assert!(temp_dir.path().join("1.log").exists());
+
+    thread::sleep(Duration::from_millis(200));
+    store.set("kept".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    assert!(!temp_dir.path().join("1.log").exists());
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("kept", "value")]));
+    Ok(())
+}
+
+// The background sweeper should reap expired keys without any further writes
+#[test]
+fn sweeper_reaps_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        sweep_interval: Some(Duration::from_millis(20)),
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+
+    for _ in 0..250 {
+        if !temp_dir.path().join("1.log").exists() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("expired keys weren't compacted away");
+}
+
+// Should apply sets and removes of a batch together and keep them across reopening and compaction
+#[test]
+fn apply_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key1".to_owned());
+    batch.remove("missing".to_owned());
+    batch.set("key3".to_owned(), "value3b".to_owned());
+    store.apply_batch(batch)?;
+
+    let expected = pairs(&[("key2", "value2"), ("key3", "value3b")]);
+    assert_eq!(collect(store.scan(..)?)?, expected);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(collect(store.scan(..)?)?, expected);
+
+    // batched values get compacted like any other
+    for iter in 0..500 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3b".to_owned()));
+
+    Ok(())
+}
+
+// A batch cut short by a crash should be dropped as a whole
+#[test]
+fn recover_torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.apply_batch(batch)?;
+    drop(store);
+
+    // cut the last record of the batch in half
+    let db_path = temp_dir.path().join("1.log");
+    let log = OpenOptions::new().write(true).open(&db_path)?;
+    log.set_len(log.metadata()?.len() - 5)?;
+    drop(log);
+
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("key1", "value1")]));
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+
+    // create only if missing
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?,
+        Err(CompareAndSwapError {
+            current: Some("value1".to_owned())
+        })
+    );
+    assert_eq!(
+        store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned())
+        )?,
+        Ok(())
+    );
+    assert_eq!(
+        store.compare_and_swap("key2".to_owned(), Some("value1".to_owned()), None)?,
+        Err(CompareAndSwapError { current: None })
+    );
+
+    // expired keys count as missing
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        store.compare_and_swap("key3".to_owned(), None, Some("value3b".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(store.ttl("key3".to_owned())?, None);
+
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?,
+        Ok(())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("key3", "value3b")]));
+
+    Ok(())
+}
+
+// Every swap should see the outcome of the previous one
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut current = store.get("counter".to_owned()).unwrap();
+                    loop {
+                        let next = current.as_ref().unwrap().parse::<u32>().unwrap() + 1;
+                        match store
+                            .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                            .unwrap()
+                        {
+                            Ok(()) => break,
+                            Err(error) => current = error.current,
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn incr_by() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr_by("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+    store.set("counter".to_owned(), "40".to_owned())?;
+    assert_eq!(store.incr_by("counter".to_owned(), 2)?, 42);
+
+    // expiry is kept
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "1".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.incr_by("expiring".to_owned(), 1)?, 2);
+    assert!(store.ttl("expiring".to_owned())?.is_some());
+
+    // nothing is written on failure
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.incr_by("text".to_owned(), 1),
+        Err(Error::InvalidCounter(_))
+    ));
+    assert!(matches!(
+        store.incr_by("counter".to_owned(), i64::MAX),
+        Err(Error::InvalidCounter(_))
+    ));
+    assert_eq!(store.get("counter".to_owned())?, Some("42".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.incr_by("counter".to_owned(), 0)?, 42);
+
+    Ok(())
+}
+
+// No increment should get lost
+#[test]
+fn concurrent_incr_by() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.incr_by("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    Ok(())
+}
+
+// A key being overwritten should never look missing to concurrent readers
+#[test]
+fn concurrent_get_during_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "0".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..2000 {
+                store.set("key1".to_owned(), iter.to_string()).unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..2000 {
+                    assert!(store.get("key1".to_owned()).unwrap().is_some());
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut transaction = store.begin();
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    transaction.set("key1".to_owned(), "value1b".to_owned());
+    transaction.remove("key2".to_owned());
+    transaction.remove("missing".to_owned());
+    transaction.set("key3".to_owned(), "value3".to_owned());
+    // reads see own writes
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("value1b".to_owned())
+    );
+    assert_eq!(transaction.get("key2".to_owned())?, None);
+    // nothing is visible before commit
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    transaction.commit()?;
+
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        pairs(&[("key1", "value1b"), ("key3", "value3")])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::transaction_conflict(KiwiStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::concurrent_transactions(KiwiStore::open(temp_dir.path())?)
+}
+
+// Snapshot should keep reading old values across writes and compaction
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    for iter in 0..500 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    // keys expiring after the snapshot stay
+    assert_eq!(
+        collect(snapshot.scan(..)?)?,
+        pairs(&[
+            ("expiring", "value"),
+            ("key1", "value1"),
+            ("key2", "value2")
+        ])
+    );
+    assert_eq!(
+        collect(snapshot.scan_prefix("key".to_owned())?)?,
+        pairs(&[("key1", "value1"), ("key2", "value2")])
+    );
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1b".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn seqs(store: &KiwiStore, key: &str) -> Result<Vec<u64>> {
+    let history = store.history(key.to_owned())?;
+    Ok(history.into_iter().map(|version| version.seq).collect())
+}
+
+// Should read past values of keys as of a sequence number, within the retention window
+#[test]
+fn history_and_get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        retention: Some(Retention {
+            versions: Some(3),
+            age: None,
+        }),
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<_> = history
+        .iter()
+        .map(|version| version.value.clone())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+            None,
+            Some("value3".to_owned())
+        ]
+    );
+    let seqs: Vec<_> = history.iter().map(|version| version.seq).collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[0])?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[1])?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_at("key1".to_owned(), seqs[2])?, None);
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[3] + 10)?,
+        Some("value3".to_owned())
+    );
+    // nothing was compacted yet, so the key didn't exist before its first write
+    assert_eq!(store.get_at("key1".to_owned(), seqs[0] - 1)?, None);
+
+    // compaction keeps the three newest versions, the history survives a reopen
+    for iter in 0..500 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+
+    assert_eq!(self::seqs(&store, "key1")?, seqs[1..].to_vec());
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[1])?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_at("key1".to_owned(), seqs[2])?, None);
+    match store.get_at("key1".to_owned(), seqs[0]) {
+        Err(Error::HistoryTruncated(seq)) => assert_eq!(seq, seqs[0]),
+        other => panic!("expected truncated history, got {:?}", other),
+    }
+    assert!(self::seqs(&store, "other")?.len() < 500);
+    assert_eq!(store.get("other".to_owned())?, Some("499".to_owned()));
+
+    // sequence numbers keep increasing after a reopen
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert!(self::seqs(&store, "key1")?[3] > seqs[3]);
+
+    Ok(())
+}
+
+// Should tell only the current value without a retention window
+#[test]
+fn history_without_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+
+    let history = store.history("key1".to_owned())?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some("value2".to_owned()));
+    let seq = history[0].seq;
+    assert_eq!(
+        store.get_at("key1".to_owned(), seq)?,
+        Some("value2".to_owned())
+    );
+    assert!(matches!(
+        store.get_at("key1".to_owned(), seq - 1),
+        Err(Error::HistoryTruncated(_))
+    ));
+
+    store.remove("key1".to_owned())?;
+    assert!(store.history("key1".to_owned())?.is_empty());
+
+    Ok(())
+}
+
+// Should deliver changes of watched keys with the sequence numbers they were written with
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "before".to_owned())?;
+
+    let mut watcher = store.watch("user:".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("other".to_owned(), "ignored".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:2".to_owned(), "bob".to_owned());
+    batch.remove("user:1".to_owned());
+    store.apply_batch(batch)?;
+    store.set_with_ttl(
+        "user:3".to_owned(),
+        "carol".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    let seq = seqs(&store, "user:2")?[0];
+    let events: Vec<_> = watcher.by_ref().take(4).collect();
+    assert_eq!(
+        events,
+        vec![
+            ChangeEvent::Set {
+                key: b"user:1".to_vec(),
+                value: b"alice".to_vec(),
+                seq: seq - 2,
+            },
+            ChangeEvent::Set {
+                key: b"user:2".to_vec(),
+                value: b"bob".to_vec(),
+                seq,
+            },
+            ChangeEvent::Remove {
+                key: b"user:1".to_vec(),
+                seq: seq + 1,
+            },
+            ChangeEvent::Set {
+                key: b"user:3".to_vec(),
+                value: b"carol".to_vec(),
+                seq: seq + 2,
+            },
+        ]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    // removing a missing key changes nothing
+    assert!(store.remove("user:1".to_owned()).is_err());
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    // watching ends with the store
+    drop(store);
+    assert_eq!(watcher.next(), None);
+
+    Ok(())
+}
+
+// Should replay writes from the log after a sequence number, until compaction merges them away
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    store.apply_batch(batch)?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    let changes = store.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes,
+        vec![
+            ChangeEvent::Set {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+                seq: 1,
+            },
+            ChangeEvent::Set {
+                key: b"key2".to_vec(),
+                value: b"value2".to_vec(),
+                seq: 2,
+            },
+            ChangeEvent::Remove {
+                key: b"key1".to_vec(),
+                seq: 3,
+            },
+            ChangeEvent::Set {
+                key: b"key3".to_vec(),
+                value: b"value3".to_vec(),
+                seq: 4,
+            },
+        ]
+    );
+    let seqs: Vec<_> = store
+        .changes_since(2)?
+        .map(|change| change.map(|change| change.seq()))
+        .collect::<Result<_>>()?;
+    assert_eq!(seqs, vec![3, 4]);
+    assert_eq!(store.changes_since(4)?.count(), 0);
+
+    // writes made later aren't part of a replay already started
+    let changes = store.changes_since(3)?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(changes.count(), 1);
+
+    // compaction merges away writes up to the horizon, later ones survive a reopen
+    for iter in 0..500 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    match store.changes_since(0) {
+        Err(Error::HistoryTruncated(seq)) => assert_eq!(seq, 0),
+        other => panic!("expected truncated history, got {:?}", other.map(|_| ())),
+    }
+    let last = store.history("other".to_owned())?[0].seq;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    let changes = store.changes_since(last)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes,
+        vec![ChangeEvent::Set {
+            key: b"key5".to_vec(),
+            value: b"value5".to_vec(),
+            seq: last + 1,
+        }]
+    );
+
+    Ok(())
+}
+
+// Should keep keys of namespaces apart, with their own watchers and replays
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    let orders = users.open_tree("orders")?;
+    let mut watcher = users.watch(String::new())?;
+
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "users".to_owned())?;
+    orders.set("key".to_owned(), "orders".to_owned())?;
+    orders.set("other".to_owned(), "orders".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("batched".to_owned(), "users".to_owned());
+    users.apply_batch(batch)?;
+    assert_eq!(users.incr_by("counter".to_owned(), 2)?, 2);
+
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert_eq!(orders.get("key".to_owned())?, Some("orders".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+    assert!(users.remove("other".to_owned()).is_err());
+    assert_eq!(
+        collect(users.scan(..)?)?,
+        pairs(&[("batched", "users"), ("counter", "2"), ("key", "users")])
+    );
+    let snapshot = orders.snapshot()?;
+    orders.remove("key".to_owned())?;
+    assert_eq!(snapshot.get("key".to_owned())?, Some("orders".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+
+    // opening a namespace again from any handle gives the same keys
+    assert_eq!(
+        store.open_tree("users")?.get("key".to_owned())?,
+        Some("users".to_owned())
+    );
+
+    let keys: Vec<_> = watcher
+        .by_ref()
+        .take(3)
+        .map(|event| event.key().to_vec())
+        .collect();
+    assert_eq!(
+        keys,
+        vec![b"key".to_vec(), b"batched".to_vec(), b"counter".to_vec()]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+    let keys = users
+        .changes_since(0)?
+        .map(|change| change.map(|change| change.key().to_vec()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        keys,
+        vec![b"key".to_vec(), b"batched".to_vec(), b"counter".to_vec()]
+    );
+
+    drop((store, users, orders, watcher));
+    let store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.get("batched".to_owned())?, None);
+    let users = store.open_tree("users")?;
+    assert_eq!(users.get("batched".to_owned())?, Some("users".to_owned()));
+    let orders = store.open_tree("orders")?;
+    assert_eq!(orders.get("key".to_owned())?, None);
+    assert_eq!(orders.get("other".to_owned())?, Some("orders".to_owned()));
+    assert_eq!(store.open_tree("new")?.scan(..)?.count(), 0);
+
+    Ok(())
+}
+
+// Should find namespaces opened before, also after reopening, without creating missing ones
+#[test]
+fn find_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KiwiStore::open(temp_dir.path())?;
+    store
+        .open_tree("users")?
+        .set("key".to_owned(), "users".to_owned())?;
+    assert!(store.find_tree("missing")?.is_none());
+    drop(store);
+
+    let store = KiwiStore::open(temp_dir.path())?;
+    let users = store.find_tree("users")?.expect("namespace not found");
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert!(store.find_tree("missing")?.is_none());
+    let names = fs::read_to_string(temp_dir.path().join("namespaces.json"))?;
+    assert!(!names.contains("missing"));
+
+    Ok(())
+}
+
+// Should keep keys in their namespaces through compaction and hint files
+#[test]
+fn compaction_of_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KiwiStoreOptions {
+        max_segment_size: 4096,
+        compaction_min_size: 4096,
+        retention: Some(Retention {
+            versions: Some(2),
+            age: None,
+        }),
+        ..KiwiStoreOptions::default()
+    };
+    let store = KiwiStore::open_with_options(temp_dir.path(), options.clone())?;
+    let tree = store.open_tree("tree")?;
+    for iter in 0..500 {
+        store.set("key".to_owned(), format!("default{}", iter))?;
+        tree.set("key".to_owned(), format!("tree{}", iter))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    drop((store, tree));
+    let store = KiwiStore::open_with_options(temp_dir.path(), options)?;
+    let tree = store.open_tree("tree")?;
+    assert_eq!(store.get("key".to_owned())?, Some("default499".to_owned()));
+    assert_eq!(tree.get("key".to_owned())?, Some("tree499".to_owned()));
+    let values: Vec<_> = tree
+        .history("key".to_owned())?
+        .into_iter()
+        .rev()
+        .take(2)
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(
+        values,
+        vec![Some("tree499".to_owned()), Some("tree498".to_owned())]
+    );
+
+    Ok(())
+}
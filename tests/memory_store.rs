@@ -0,0 +1,332 @@
+mod common;
+
+use common::{collect, pairs};
+use kiwi_store::{
+    ChangeEvent, Error, KiwiEngine, KiwiStore, MemoryStore, Result, Retention, WriteBatch,
+};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let store = MemoryStore::new();
+    common::scan_range_and_prefix(store.clone())?;
+    assert!(matches!(store.remove("b".to_owned()), Err(Error::NoKey(_))));
+
+    // keys removed while scanning are skipped
+    let mut scan = store.scan(..)?;
+    store.remove("c".to_owned())?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("a".to_owned(), "v-a".to_owned()))
+    );
+    assert_eq!(
+        scan.next().transpose()?,
+        Some(("user:1".to_owned(), "v-user:1".to_owned()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    common::binary_keys_and_values(MemoryStore::new())
+}
+
+// Expired keys should be invisible to reads and left out of saved snapshots
+#[test]
+fn keys_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryStore::new();
+
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "cached".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "later".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.get("cached".to_owned())?, Some("value".to_owned()));
+    assert!(store.ttl("cached".to_owned())?.is_some());
+    assert_eq!(store.ttl("kept".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("cached".to_owned())?, None);
+    assert_eq!(store.ttl("cached".to_owned())?, None);
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        pairs(&[("kept", "value"), ("later", "value")])
+    );
+
+    store.save(temp_dir.path())?;
+    drop(store);
+    let store = MemoryStore::load(temp_dir.path())?;
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("cached".to_owned())?, None);
+    assert!(store.ttl("later".to_owned())?.is_some());
+    Ok(())
+}
+
+// Should apply sets and removes of a batch together
+#[test]
+fn apply_write_batch() -> Result<()> {
+    let store = MemoryStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    );
+    batch.remove("key1".to_owned());
+    batch.remove("missing".to_owned());
+    store.apply_batch(batch)?;
+
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        pairs(&[("key2", "value2"), ("key3", "value3")])
+    );
+    assert!(store.ttl("key3".to_owned())?.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    common::compare_and_swap(MemoryStore::new())
+}
+
+#[test]
+fn incr_by() -> Result<()> {
+    common::incr_by(MemoryStore::new())
+}
+
+#[test]
+fn transaction_conflict() -> Result<()> {
+    common::transaction_conflict(MemoryStore::new())
+}
+
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    common::concurrent_transactions(MemoryStore::new())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    common::snapshot(MemoryStore::new())
+}
+
+// Should record every write with retention and drop versions beyond it in the background
+#[test]
+fn history_and_get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let retention = Retention {
+        versions: Some(3),
+        age: None,
+    };
+    let store = MemoryStore::with_retention(retention);
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store
+        .compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?
+        .unwrap();
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.set("key2".to_owned(), "value1".to_owned());
+    store.apply_batch(batch)?;
+
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<_> = history
+        .iter()
+        .map(|version| version.value.clone())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Some("value0".to_owned()),
+            Some("value1".to_owned()),
+            None,
+            Some("value2".to_owned()),
+            Some("value3".to_owned())
+        ]
+    );
+    let seqs: Vec<_> = history.iter().map(|version| version.seq).collect();
+    assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[1])?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_at("key1".to_owned(), seqs[2])?, None);
+    assert_eq!(store.get_at("key2".to_owned(), seqs[3])?, None);
+
+    // the sweeper keeps the three newest versions
+    for _ in 0..250 {
+        if store.history("key1".to_owned())?.len() <= 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let history = store.history("key1".to_owned())?;
+    let kept: Vec<_> = history.iter().map(|version| version.seq).collect();
+    assert_eq!(kept, seqs[2..].to_vec());
+    assert!(matches!(
+        store.get_at("key1".to_owned(), seqs[1]),
+        Err(Error::HistoryTruncated(_))
+    ));
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[3])?,
+        Some("value2".to_owned())
+    );
+
+    // versions and the horizon survive a save
+    store.save(temp_dir.path())?;
+    drop(store);
+    let store = MemoryStore::load_with_retention(temp_dir.path(), retention)?;
+    assert_eq!(store.history("key1".to_owned())?, history);
+    assert!(matches!(
+        store.get_at("key1".to_owned(), seqs[1]),
+        Err(Error::HistoryTruncated(_))
+    ));
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.history("key1".to_owned())?.last().unwrap().seq, 7);
+    drop(store);
+
+    // loading without retention keeps current values only
+    let store = MemoryStore::load(temp_dir.path())?;
+    let values: Vec<_> = store
+        .history("key1".to_owned())?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(values, vec![Some("value3".to_owned())]);
+
+    Ok(())
+}
+
+#[test]
+fn watch() -> Result<()> {
+    let store = MemoryStore::new();
+
+    let mut watcher = store.watch("user:".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("other".to_owned(), "ignored".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:2".to_owned(), "bob".to_owned());
+    store.apply_batch(batch)?;
+    store.remove("user:1".to_owned())?;
+
+    let events: Vec<_> = watcher.by_ref().take(3).collect();
+    assert_eq!(
+        events,
+        vec![
+            ChangeEvent::Set {
+                key: b"user:1".to_vec(),
+                value: b"alice".to_vec(),
+                seq: 1,
+            },
+            ChangeEvent::Set {
+                key: b"user:2".to_vec(),
+                value: b"bob".to_vec(),
+                seq: 3,
+            },
+            ChangeEvent::Remove {
+                key: b"user:1".to_vec(),
+                seq: 4,
+            },
+        ]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    Ok(())
+}
+
+// Should keep keys of namespaces apart, and their names across a save
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryStore::new();
+    let users = store.open_tree("users")?;
+    let mut watcher = users.watch(String::new())?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "users".to_owned())?;
+    users.set("other".to_owned(), "users".to_owned())?;
+    store.open_tree("empty")?;
+
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+    assert_eq!(
+        collect(store.open_tree("users")?.scan(..)?)?,
+        pairs(&[("key", "users"), ("other", "users")])
+    );
+    let keys: Vec<_> = watcher
+        .by_ref()
+        .take(2)
+        .map(|event| event.key().to_vec())
+        .collect();
+    assert_eq!(keys, vec![b"key".to_vec(), b"other".to_vec()]);
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    store.save(temp_dir.path())?;
+    drop((store, users));
+    let store = MemoryStore::load(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    // names are kept even without keys, new ones get ids of their own
+    assert_eq!(collect(store.open_tree("empty")?.scan(..)?)?, pairs(&[]));
+    let orders = store.open_tree("orders")?;
+    orders.set("key".to_owned(), "orders".to_owned())?;
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+
+    Ok(())
+}
+
+// A saved snapshot should be a log KiwiStore can open, and the other way round
+#[test]
+fn kiwi_store_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemoryStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store
+        .open_tree("users")?
+        .set("key1".to_owned(), "users".to_owned())?;
+    store.save(temp_dir.path())?;
+
+    let kiwi_store = KiwiStore::open(temp_dir.path())?;
+    assert_eq!(collect(kiwi_store.scan(..)?)?, pairs(&[("key1", "value1")]));
+    assert_eq!(
+        kiwi_store.open_tree("users")?.get("key1".to_owned())?,
+        Some("users".to_owned())
+    );
+    kiwi_store.set("key3".to_owned(), "value3".to_owned())?;
+    kiwi_store.remove("key1".to_owned())?;
+    drop(kiwi_store);
+
+    let store = MemoryStore::load(temp_dir.path())?;
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("key3", "value3")]));
+    assert_eq!(
+        store.open_tree("users")?.get("key1".to_owned())?,
+        Some("users".to_owned())
+    );
+
+    // saving again replaces all segments
+    store.save(temp_dir.path())?;
+    let store = MemoryStore::load(temp_dir.path())?;
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("key3", "value3")]));
+
+    // a missing directory loads as an empty store
+    let store = MemoryStore::load(temp_dir.path().join("missing"))?;
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[]));
+
+    Ok(())
+}
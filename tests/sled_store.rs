@@ -0,0 +1,459 @@
+mod common;
+
+use common::{collect, pairs};
+use kiwi_store::{ChangeEvent, Error, KiwiEngine, Result, Retention, SledStore, WriteBatch};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::scan_range_and_prefix(SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::binary_keys_and_values(SledStore::open(temp_dir.path())?)
+}
+
+// sled releases its lock in the background once the last handle is dropped, so retry for a while
+fn reopen(open: impl Fn() -> Result<SledStore>) -> Result<SledStore> {
+    let mut opened = open();
+    for _ in 0..250 {
+        if opened.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        opened = open();
+    }
+    opened
+}
+
+// Expired keys should be invisible to reads
+#[test]
+fn keys_expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "cached".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert_eq!(store.get("cached".to_owned())?, Some("value".to_owned()));
+    assert!(store.ttl("cached".to_owned())?.is_some());
+    assert_eq!(store.ttl("kept".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("cached".to_owned())?, None);
+    assert_eq!(store.ttl("cached".to_owned())?, None);
+    assert_eq!(collect(store.scan(..)?)?, pairs(&[("kept", "value")]));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = reopen(|| SledStore::open(temp_dir.path()))?;
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("cached".to_owned())?, None);
+    Ok(())
+}
+
+// The background sweeper should remove expired keys, but not ones set again without an expiry
+#[test]
+fn sweeper_removes_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(100);
+    store.set_with_ttl("cached".to_owned(), "value".to_owned(), ttl)?;
+    store.set_with_ttl("renewed".to_owned(), "value".to_owned(), ttl)?;
+    store.set("renewed".to_owned(), "kept".to_owned())?;
+
+    let mut watcher = store.watch(String::new())?;
+    assert_eq!(
+        watcher.next_timeout(Duration::from_secs(3)),
+        Some(ChangeEvent::Remove {
+            key: b"cached".to_vec(),
+            seq: 1,
+        })
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(1500)), None);
+    assert_eq!(store.get("renewed".to_owned())?, Some("kept".to_owned()));
+
+    Ok(())
+}
+
+// Values written through sled directly before the store was opened should be read as they are
+#[test]
+fn read_plain_sled_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    db.insert("short", "v")?;
+    db.insert("long", "a value longer than eight bytes")?;
+    db.insert(vec![0xff, 0x00], vec![0x89, b'P', b'N', b'G', 0x00, 0xff])?;
+    db.flush()?;
+    drop(db);
+
+    let store = reopen(|| SledStore::open(temp_dir.path()))?;
+    assert_eq!(store.get("short".to_owned())?, Some("v".to_owned()));
+    assert_eq!(
+        store.get("long".to_owned())?,
+        Some("a value longer than eight bytes".to_owned())
+    );
+    assert_eq!(
+        store.get_bytes(&[0xff, 0x00])?,
+        Some(vec![0x89, b'P', b'N', b'G', 0x00, 0xff])
+    );
+    store.set("new".to_owned(), "value".to_owned())?;
+
+    // values are encoded only once
+    drop(store);
+    let store = reopen(|| SledStore::open(temp_dir.path()))?;
+    assert_eq!(store.get("short".to_owned())?, Some("v".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should apply sets and removes of a batch together
+#[test]
+fn apply_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    );
+    batch.remove("key1".to_owned());
+    batch.remove("missing".to_owned());
+    store.apply_batch(batch)?;
+
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        pairs(&[("key2", "value2"), ("key3", "value3")])
+    );
+    assert!(store.ttl("key3".to_owned())?.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::compare_and_swap(SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn incr_by() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::incr_by(SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut transaction = store.begin();
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    transaction.set("key1".to_owned(), "value1b".to_owned());
+    transaction.remove("key2".to_owned());
+    transaction.remove("missing".to_owned());
+    transaction.set("key3".to_owned(), "value3".to_owned());
+    // reads see own writes
+    assert_eq!(
+        transaction.get("key1".to_owned())?,
+        Some("value1b".to_owned())
+    );
+    assert_eq!(transaction.get("key2".to_owned())?, None);
+    // nothing is visible before commit
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    transaction.commit()?;
+
+    assert_eq!(
+        collect(store.scan(..)?)?,
+        pairs(&[("key1", "value1b"), ("key3", "value3")])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::transaction_conflict(SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::concurrent_transactions(SledStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    common::snapshot(SledStore::open(temp_dir.path())?)
+}
+
+// Should record every write with retention and drop versions beyond it in the background
+#[test]
+fn history_and_get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    assert!(store.history("key1".to_owned())?.is_empty());
+    drop(store);
+
+    let retention = Retention {
+        versions: Some(3),
+        age: None,
+    };
+    let store = reopen(|| SledStore::open_with_retention(temp_dir.path(), retention))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store
+        .compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?
+        .unwrap();
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value3".to_owned());
+    batch.set("key2".to_owned(), "value1".to_owned());
+    store.apply_batch(batch)?;
+
+    // the history starts with the value the key had when retention was enabled
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<_> = history
+        .iter()
+        .map(|version| version.value.clone())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            Some("value0".to_owned()),
+            Some("value1".to_owned()),
+            None,
+            Some("value2".to_owned()),
+            Some("value3".to_owned())
+        ]
+    );
+    let seqs: Vec<_> = history.iter().map(|version| version.seq).collect();
+    assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[1])?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_at("key1".to_owned(), seqs[2])?, None);
+    assert_eq!(store.get_at("key2".to_owned(), seqs[3])?, None);
+    match store.get_at("key1".to_owned(), seqs[0] - 1) {
+        Err(Error::HistoryTruncated(seq)) => assert_eq!(seq, seqs[0] - 1),
+        other => panic!("expected truncated history, got {:?}", other),
+    }
+
+    // the sweeper keeps the three newest versions
+    for _ in 0..250 {
+        if store.history("key1".to_owned())?.len() <= 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let history = store.history("key1".to_owned())?;
+    let kept: Vec<_> = history.iter().map(|version| version.seq).collect();
+    assert_eq!(kept, seqs[2..].to_vec());
+    assert!(matches!(
+        store.get_at("key1".to_owned(), seqs[1]),
+        Err(Error::HistoryTruncated(_))
+    ));
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[3])?,
+        Some("value2".to_owned())
+    );
+    drop(store);
+
+    // opening without retention keeps the history, but doesn't record writes
+    let store = reopen(|| SledStore::open(temp_dir.path()))?;
+    assert!(store.history("key1".to_owned())?.is_empty());
+    assert!(matches!(
+        store.get_at("key1".to_owned(), seqs[4]),
+        Err(Error::HistoryTruncated(_))
+    ));
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // the history catches up with them when opened with retention again
+    let store = reopen(|| SledStore::open_with_retention(temp_dir.path(), retention))?;
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<_> = history
+        .iter()
+        .map(|version| version.value.clone())
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            None,
+            Some("value2".to_owned()),
+            Some("value3".to_owned()),
+            Some("value4".to_owned())
+        ]
+    );
+    let caught_up = history[3].seq;
+    assert_eq!(
+        store.get_at("key1".to_owned(), seqs[4])?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get_at("key2".to_owned(), caught_up)?, None);
+    assert_eq!(
+        store.get_at("key2".to_owned(), caught_up - 1)?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_at("key3".to_owned(), caught_up)?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Versions kept for their age should be dropped once they age out, without further writes
+#[test]
+fn history_ages_out() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let retention = Retention {
+        versions: None,
+        age: Some(Duration::from_millis(1500)),
+    };
+    let store = SledStore::open_with_retention(temp_dir.path(), retention)?;
+    // let the sweeper go through the whole history once
+    thread::sleep(Duration::from_millis(1200));
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(store.history("key".to_owned())?.len(), 2);
+
+    thread::sleep(Duration::from_millis(1500));
+    let history = store.history("key".to_owned())?;
+    let values: Vec<_> = history.into_iter().map(|version| version.value).collect();
+    assert_eq!(values, vec![Some("value2".to_owned())]);
+
+    Ok(())
+}
+
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+
+    let mut watcher = store.watch("user:".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("other".to_owned(), "ignored".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:2".to_owned(), "bob".to_owned());
+    store.apply_batch(batch)?;
+    store.remove("user:1".to_owned())?;
+
+    let events: Vec<_> = watcher.by_ref().take(3).collect();
+    assert_eq!(
+        events,
+        vec![
+            ChangeEvent::Set {
+                key: b"user:1".to_vec(),
+                value: b"alice".to_vec(),
+                seq: 1,
+            },
+            ChangeEvent::Set {
+                key: b"user:2".to_vec(),
+                value: b"bob".to_vec(),
+                seq: 2,
+            },
+            ChangeEvent::Remove {
+                key: b"user:1".to_vec(),
+                seq: 3,
+            },
+        ]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    Ok(())
+}
+
+// Should find namespaces opened before, also after reopening, without creating missing ones
+#[test]
+fn find_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store
+        .open_tree("users")?
+        .set("key".to_owned(), "users".to_owned())?;
+    assert!(store.find_tree("missing")?.is_none());
+    drop(store);
+
+    let store = reopen(|| SledStore::open(temp_dir.path()))?;
+    let users = store.find_tree("users")?.expect("namespace not found");
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert!(store.find_tree("missing")?.is_none());
+
+    Ok(())
+}
+
+// Should keep keys of namespaces apart in trees of their own, each with its own history
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "users0".to_owned())?;
+    users.set("other".to_owned(), "users".to_owned())?;
+    drop((store, users));
+
+    let retention = Retention {
+        versions: Some(10),
+        age: None,
+    };
+    let store = reopen(|| SledStore::open_with_retention(temp_dir.path(), retention))?;
+    let users = store.open_tree("users")?;
+    let mut watcher = users.watch(String::new())?;
+    users.set("key".to_owned(), "users1".to_owned())?;
+    store.set("key".to_owned(), "ignored".to_owned())?;
+    users.remove("other".to_owned())?;
+
+    assert_eq!(store.get("key".to_owned())?, Some("ignored".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users1".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+    assert_eq!(
+        collect(store.open_tree("users")?.scan(..)?)?,
+        pairs(&[("key", "users1")])
+    );
+    let values: Vec<_> = users
+        .history("key".to_owned())?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(
+        values,
+        vec![Some("users0".to_owned()), Some("users1".to_owned())]
+    );
+    assert_eq!(store.history("key".to_owned())?.len(), 2);
+    let keys: Vec<_> = watcher
+        .by_ref()
+        .take(2)
+        .map(|event| event.key().to_vec())
+        .collect();
+    assert_eq!(keys, vec![b"key".to_vec(), b"other".to_vec()]);
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    Ok(())
+}
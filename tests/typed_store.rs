@@ -0,0 +1,88 @@
+use kiwi_store::codec::{Codec, Json};
+use kiwi_store::{Error, KiwiEngine, KiwiStore, Result, SledStore, TypedStore};
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct UserId(u64);
+
+fn user() -> User {
+    User {
+        name: "kiwi".to_owned(),
+        age: 7,
+        tags: vec!["bird".to_owned(), "fruit".to_owned()],
+    }
+}
+
+fn roundtrip<E: KiwiEngine, C: Codec>(engine: E) -> Result<()> {
+    let store: TypedStore<E, UserId, User, C> = TypedStore::new(engine);
+
+    store.set(&UserId(1), &user())?;
+    assert_eq!(store.get(&UserId(1))?, Some(user()));
+    assert_eq!(store.get(&UserId(2))?, None);
+
+    store.remove(&UserId(1))?;
+    assert_eq!(store.get(&UserId(1))?, None);
+
+    Ok(())
+}
+
+// Should store and load typed values with the JSON codec in both engines
+#[test]
+fn json_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    roundtrip::<_, Json>(KiwiStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    roundtrip::<_, Json>(SledStore::open(temp_dir.path())?)
+}
+
+// JSON-encoded values should be readable through the string API as well
+#[test]
+fn json_is_plain_text() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KiwiStore::open(temp_dir.path())?;
+    let store: TypedStore<_, String, u32> = TypedStore::new(engine.clone());
+
+    store.set(&"answer".to_owned(), &42)?;
+    assert_eq!(engine.get("\"answer\"".to_owned())?, Some("42".to_owned()));
+
+    Ok(())
+}
+
+// Should fail with a codec error when stored bytes aren't the expected type
+#[test]
+fn decode_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KiwiStore::open(temp_dir.path())?;
+    let store: TypedStore<_, String, User> = TypedStore::new(engine.clone());
+
+    engine.set("\"user\"".to_owned(), "not a user".to_owned())?;
+    assert!(matches!(
+        store.get(&"user".to_owned()),
+        Err(Error::Codec(_))
+    ));
+
+    Ok(())
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    roundtrip::<_, kiwi_store::codec::Bincode>(KiwiStore::open(temp_dir.path())?)
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    roundtrip::<_, kiwi_store::codec::MsgPack>(KiwiStore::open(temp_dir.path())?)
+}